@@ -0,0 +1,484 @@
+//! Generates the keyberon `Layers` of the firmware from `keymap.toml`,
+//! `keymap-split.toml` with the `split` feature, or from the file named
+//! by the `KEEB_KEYMAP` environment variable.
+//!
+//! The keymap is checked here, so that a typo in a key name or a row of
+//! the wrong length fails the build with a message pointing at it,
+//! rather than with a type error in generated code. See `keymap.toml`
+//! for the format.
+//!
+//! keyberon 0.1 has no custom actions, so each custom action named in
+//! the keymap, like `Mouse(Up)` or `ToggleReportMode`, gets a key code the keymap does not use
+//! otherwise, and `CUSTOM_ACTIONS` maps these key codes back to their
+//! `keeb::layout::Custom` action.
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use keyberon::key_code::KeyCode;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=KEEB_KEYMAP");
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let split = env::var_os("CARGO_FEATURE_SPLIT").is_some();
+    let default = match split {
+        true => "keymap-split.toml",
+        false => "keymap.toml",
+    };
+    let path = env::var_os("KEEB_KEYMAP")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| manifest_dir.join(default));
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let code = match generate(&path, split) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("keymap.rs"), code).unwrap();
+
+    // the golden traces and their keymaps, for the tests of src/trace.rs
+    let traces = manifest_dir.join("traces");
+    println!("cargo:rerun-if-changed={}", traces.display());
+    let code = match generate_traces(&traces) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+    fs::write(out.join("traces.rs"), code).unwrap();
+}
+
+/// Returns the Rust code of a module for every golden trace of `dir`,
+/// `<name>.golden`, holding the items of its keymap, `<name>.toml`, its
+/// text as `GOLDEN`, and a `golden_test!()` to check one with the other.
+fn generate_traces(dir: &Path) -> Result<String, String> {
+    let entries = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+    let mut goldens = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("{}: {}", dir.display(), err))?;
+    goldens.retain(|path| path.extension().is_some_and(|ext| ext == "golden"));
+    goldens.sort();
+
+    let mut code = String::new();
+    for golden in goldens {
+        let name = golden.file_stem().unwrap().to_string_lossy();
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("{}: not a Rust name", golden.display()));
+        }
+        let keymap = golden.with_extension("toml");
+        let items =
+            generate(&keymap, false).map_err(|err| format!("{}: {}", keymap.display(), err))?;
+        writeln!(code, "mod {} {{", name).unwrap();
+        code += &items;
+        writeln!(code, "pub const GOLDEN: &str = include_str!({:?});", golden).unwrap();
+        writeln!(code, "golden_test!();").unwrap();
+        writeln!(code, "}}").unwrap();
+    }
+    Ok(code)
+}
+
+/// Reads and checks the keymap at `path`, and returns the Rust code of
+/// its `ROWS`, `COLS`, debouncing, `LAYERS` and `CUSTOM_ACTIONS` items.
+/// A `split` keymap is made of two halves of the same width, side by
+/// side.
+fn generate(path: &Path, split: bool) -> Result<String, String> {
+    let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let keymap: toml::Table = text
+        .parse()
+        .map_err(|err: toml::de::Error| err.to_string())?;
+
+    let rows = get_size(&keymap, "rows")?;
+    let cols = get_size(&keymap, "cols")?;
+    if split && cols % 2 != 0 {
+        return Err(format!(
+            "the `split` feature needs an even number of `cols`, half on each side, not {}",
+            cols
+        ));
+    }
+    let debounce = match keymap.get("debounce") {
+        None => "eager",
+        Some(toml::Value::String(name)) => name.as_str(),
+        Some(_) => return Err("`debounce` must be a debouncer name in quotes".into()),
+    };
+    let debouncer = match debounce {
+        "eager" => "EagerPressDeferRelease",
+        "defer" => "SymmetricDefer",
+        "row" => "PerRow",
+        "key" => "PerKeyCounter",
+        _ => {
+            return Err(format!(
+                "unknown debouncer `{}`, expected `eager`, `defer`, `row` or `key`",
+                debounce
+            ))
+        }
+    };
+    let debounce_ms = match keymap.get("debounce_ms") {
+        None => 5,
+        Some(_) => get_size(&keymap, "debounce_ms")?,
+    };
+    let layers = match keymap.get("layers") {
+        Some(toml::Value::Array(layers)) if !layers.is_empty() => layers,
+        _ => return Err("expected at least one `[[layers]]` table".into()),
+    };
+
+    let names = key_code_names();
+    let mut customs = Customs::default();
+    let mut code = String::new();
+    writeln!(code, "pub const ROWS: usize = {};", rows).unwrap();
+    writeln!(code, "pub const COLS: usize = {};", cols).unwrap();
+    // the firmware uses the type, the simulator picks its own debouncer
+    // by name
+    writeln!(code, "#[allow(dead_code)]").unwrap();
+    writeln!(code, "pub const DEBOUNCE: &str = {:?};", debounce).unwrap();
+    writeln!(code, "pub const DEBOUNCE_MS: u8 = {};", debounce_ms).unwrap();
+    writeln!(code, "#[allow(dead_code)]").unwrap();
+    writeln!(
+        code,
+        "pub type KeyDebouncer<const R: usize, const C: usize> = keeb::debounce::{}<R, C>;",
+        debouncer
+    )
+    .unwrap();
+    writeln!(code, "pub static LAYERS: keyberon::layout::Layers = &[").unwrap();
+    for (l, layer) in layers.iter().enumerate() {
+        let name = layer.get("name").and_then(|name| name.as_str());
+        let at = match name {
+            Some(name) => format!("layer {} (`{}`)", l, name),
+            None => format!("layer {}", l),
+        };
+        let keys = match layer.get("keys") {
+            Some(toml::Value::Array(keys)) => keys,
+            _ => return Err(format!("{}: expected a `keys` array of rows", at)),
+        };
+        if keys.len() != rows {
+            return Err(format!(
+                "{}: has {} rows, expected {}",
+                at,
+                keys.len(),
+                rows
+            ));
+        }
+
+        writeln!(code, "    &[").unwrap();
+        for (r, row) in keys.iter().enumerate() {
+            let row = match row {
+                toml::Value::Array(row) => row,
+                _ => return Err(format!("{}, row {}: expected an array of keys", at, r)),
+            };
+            if row.len() != cols {
+                return Err(format!(
+                    "{}, row {}: has {} keys, expected {}",
+                    at,
+                    r,
+                    row.len(),
+                    cols
+                ));
+            }
+
+            write!(code, "        &[").unwrap();
+            for (c, key) in row.iter().enumerate() {
+                let at = format!("{}, row {}, column {}", at, r, c);
+                let key = key
+                    .as_str()
+                    .ok_or_else(|| format!("{}: expected a key name in quotes", at))?;
+                let action = Parser::new(key, &names, layers.len(), &mut customs)
+                    .parse()
+                    .map_err(|err| format!("{}: `{}`: {}", at, key, err))?;
+                write!(code, "{}, ", action).unwrap();
+            }
+            writeln!(code, "],").unwrap();
+        }
+        writeln!(code, "    ],").unwrap();
+    }
+    writeln!(code, "];").unwrap();
+
+    writeln!(
+        code,
+        "pub static CUSTOM_ACTIONS: &[(keyberon::key_code::KeyCode, keeb::layout::Custom)] = &["
+    )
+    .unwrap();
+    // the key codes least likely to be wanted, from the end of the page
+    let mut free = (0x04..=0xA4)
+        .rev()
+        .map(|code| &names[code])
+        .filter(|name| !customs.key_codes.contains(*name));
+    for (i, action) in customs.actions.iter().enumerate() {
+        let name = free
+            .next()
+            .ok_or("the keymap uses too many key codes to give its custom actions one")?;
+        let key_code = format!("keyberon::key_code::KeyCode::{}", name);
+        code = code.replace(&Customs::placeholder(i), &key_code);
+        writeln!(code, "    ({}, {}),", key_code, action).unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    Ok(code)
+}
+
+fn get_size(keymap: &toml::Table, key: &str) -> Result<usize, String> {
+    match keymap.get(key).and_then(|value| value.as_integer()) {
+        Some(size @ 1..=255) => Ok(size as usize),
+        Some(size) => Err(format!("`{}` must be between 1 and 255, not {}", key, size)),
+        None => Err(format!("expected a `{} = <number>` entry", key)),
+    }
+}
+
+/// Returns the names of every `KeyCode` variant, as written in Rust.
+fn key_code_names() -> Vec<String> {
+    (0x00..=0xA4u8)
+        .chain(0xE0..=0xFB)
+        // Safety: KeyCode is a repr(u8) enum with variants for each of
+        // these values.
+        .map(|code| unsafe { std::mem::transmute::<u8, KeyCode>(code) })
+        .map(|kc| format!("{:?}", kc))
+        .collect()
+}
+
+/// The custom actions of the keymap, and the key codes it uses.
+#[derive(Default)]
+struct Customs {
+    /// The Rust expression of each custom action, in the order they were
+    /// first found.
+    actions: Vec<String>,
+    /// The names of the key codes of the keymap, which custom actions
+    /// must not use.
+    key_codes: BTreeSet<String>,
+}
+
+impl Customs {
+    /// Returns the text standing for the key code of the `i`th custom
+    /// action in the generated code, until it is known.
+    fn placeholder(i: usize) -> String {
+        format!("@custom{}@", i)
+    }
+
+    /// Returns the placeholder of the key code of `action`.
+    fn key_code(&mut self, action: String) -> String {
+        let i = match self.actions.iter().position(|known| *known == action) {
+            Some(i) => i,
+            None => {
+                self.actions.push(action);
+                self.actions.len() - 1
+            }
+        };
+        Self::placeholder(i)
+    }
+}
+
+/// Parses a key of the keymap into the Rust expression of its `Action`.
+///
+/// ```text
+/// action := term ('+' term)*
+/// term   := name ('(' arg (',' arg)* ')')?
+/// arg    := number | action
+/// ```
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    names: &'a [String],
+    layers: usize,
+    customs: &'a mut Customs,
+}
+
+/// A parsed term, telling plain key codes apart so that a combination of
+/// them can be a `MultipleKeyCodes`.
+enum Term {
+    KeyCode(String),
+    Action(String),
+}
+
+impl Term {
+    fn into_action(self) -> String {
+        match self {
+            Term::KeyCode(kc) => format!("keyberon::action::Action::KeyCode({})", kc),
+            Term::Action(action) => action,
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str, names: &'a [String], layers: usize, customs: &'a mut Customs) -> Self {
+        Self {
+            input,
+            pos: 0,
+            names,
+            layers,
+            customs,
+        }
+    }
+
+    fn parse(mut self) -> Result<String, String> {
+        let action = self.action()?;
+        self.skip_spaces();
+        match self.peek() {
+            None => Ok(action),
+            Some(c) => Err(format!("unexpected `{}` at column {}", c, self.pos + 1)),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(format!("expected `{}` at column {}", c, self.pos + 1)),
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, String> {
+        self.skip_spaces();
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.pos += 1;
+        }
+        match start == self.pos {
+            true => Err(format!("expected a key name at column {}", start + 1)),
+            false => Ok(&self.input[start..self.pos]),
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| format!("expected a number, found `{}`", word))
+    }
+
+    fn action(&mut self) -> Result<String, String> {
+        let mut terms = vec![self.term()?];
+        while self.eat('+') {
+            terms.push(self.term()?);
+        }
+        if terms.len() == 1 {
+            return Ok(terms.pop().unwrap().into_action());
+        }
+        if terms.iter().all(|term| matches!(term, Term::KeyCode(_))) {
+            let kcs: Vec<_> = terms
+                .into_iter()
+                .map(|term| match term {
+                    Term::KeyCode(kc) => kc,
+                    Term::Action(_) => unreachable!(),
+                })
+                .collect();
+            return Ok(format!(
+                "keyberon::action::Action::MultipleKeyCodes(&[{}])",
+                kcs.join(", ")
+            ));
+        }
+        let actions: Vec<_> = terms.into_iter().map(Term::into_action).collect();
+        Ok(format!(
+            "keyberon::action::Action::MultipleActions(&[{}])",
+            actions.join(", ")
+        ))
+    }
+
+    fn layer(&mut self) -> Result<usize, String> {
+        let layer = self.number()?;
+        match layer < self.layers {
+            true => Ok(layer),
+            false => Err(format!(
+                "there is no layer {}, the keymap has {}",
+                layer, self.layers
+            )),
+        }
+    }
+
+    fn term(&mut self) -> Result<Term, String> {
+        let name = self.word()?;
+        let action = match name {
+            "_" => "keyberon::action::Action::Trans".to_string(),
+            "NoOp" => "keyberon::action::Action::NoOp".to_string(),
+            "ToggleReportMode" => {
+                let action = "keeb::layout::Custom::ToggleReportMode".to_string();
+                return Ok(Term::KeyCode(self.customs.key_code(action)));
+            }
+            "Layer" | "DefaultLayer" => {
+                self.expect('(')?;
+                let layer = self.layer()?;
+                self.expect(')')?;
+                format!("keyberon::action::Action::{}({})", name, layer)
+            }
+            "HoldTap" => {
+                self.expect('(')?;
+                let timeout = self.number()?;
+                if timeout > u16::MAX as usize {
+                    return Err(format!("timeout {} is too long", timeout));
+                }
+                self.expect(',')?;
+                let hold = self.action()?;
+                self.expect(',')?;
+                let tap = self.action()?;
+                self.expect(')')?;
+                format!(
+                    "keyberon::action::Action::HoldTap {{ timeout: {}, hold: &{}, tap: &{} }}",
+                    timeout, hold, tap
+                )
+            }
+            "Mouse" | "MouseButton" => {
+                self.expect('(')?;
+                let arg = self.word()?;
+                self.expect(')')?;
+                let key = match (name, arg) {
+                    (
+                        "Mouse",
+                        "Up" | "Down" | "Left" | "Right" | "WheelUp" | "WheelDown" | "WheelLeft"
+                        | "WheelRight",
+                    ) => format!("keeb::mouse::MouseKey::{}", arg),
+                    ("MouseButton", "Left" | "Right" | "Middle" | "Back" | "Forward") => {
+                        format!(
+                            "keeb::mouse::MouseKey::Button(keeb::hid::MouseButton::{})",
+                            arg
+                        )
+                    }
+                    _ => return Err(format!("unknown `{}` argument `{}`", name, arg)),
+                };
+                let action = format!("keeb::layout::Custom::Mouse({})", key);
+                return Ok(Term::KeyCode(self.customs.key_code(action)));
+            }
+            _ if self.names.iter().any(|known| known == name) => {
+                self.customs.key_codes.insert(name.to_string());
+                return Ok(Term::KeyCode(format!(
+                    "keyberon::key_code::KeyCode::{}",
+                    name
+                )));
+            }
+            _ => {
+                let hint = self
+                    .names
+                    .iter()
+                    .find(|known| known.eq_ignore_ascii_case(name))
+                    .map(|known| format!(", did you mean `{}`?", known))
+                    .unwrap_or_default();
+                return Err(format!("unknown key name `{}`{}", name, hint));
+            }
+        };
+        Ok(Term::Action(action))
+    }
+}
@@ -0,0 +1,155 @@
+//! # keeb simulator
+//!
+//! Runs the firmware's debounce → layout → `NKROReport` pipeline on the
+//! host, with the keymap of `keymap.toml` and its custom actions, against
+//! a scripted timeline of switch events (see `keeb::trace` for the
+//! format), and prints every keyboard report it sends, decoded to key
+//! names.
+//!
+//! ```text
+//! cargo run --features simulator --bin sim --target x86_64-unknown-linux-gnu -- trace.txt
+//! ```
+//!
+//! Options:
+//!
+//! * `--debounce <eager|defer|row|key>`: the debouncing algorithm, the
+//!   `debounce` of the keymap by default, like the firmware.
+//! * `--debounce-ms <ms>`: the debounce delay, the `debounce_ms` of the
+//!   keymap by default.
+//! * `--tail <ms>`: how long to keep running after the last event, 1000
+//!   by default, so that hold timeouts expire.
+//! * `--bytes`: print the bytes of each report, in the format of golden
+//!   traces, followed by the key names as a comment.
+//! * `--check`: read a golden trace instead, and check that it produces
+//!   exactly the expected reports (see `keeb::trace::check`).
+//!
+//! The trace is read from standard input when no file, or `-`, is given.
+//! Reports whose BOOT part disagrees with their bitmap are pointed out on
+//! standard error (see `NKROReport::mismatches`).
+use std::io::Read;
+use std::process;
+use std::{env, fs, io};
+
+use keeb::debounce::{Debouncer, EagerPressDeferRelease, PerKeyCounter, PerRow, SymmetricDefer};
+use keeb::layout::{Custom, Keyboard};
+use keeb::trace::{self, Trace};
+
+/// The keymap, generated by build.rs, the same as the firmware's.
+mod keymap {
+    include!(concat!(env!("OUT_DIR"), "/keymap.rs"));
+}
+
+struct Options {
+    trace: Option<String>,
+    debounce: String,
+    debounce_ms: u8,
+    tail: u32,
+    bytes: bool,
+    check: bool,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        trace: None,
+        debounce: keymap::DEBOUNCE.into(),
+        debounce_ms: keymap::DEBOUNCE_MS,
+        tail: 1000,
+        bytes: false,
+        check: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--debounce" => options.debounce = value("--debounce")?,
+            "--debounce-ms" => {
+                options.debounce_ms = value("--debounce-ms")?
+                    .parse()
+                    .map_err(|err| format!("--debounce-ms: {}", err))?
+            }
+            "--tail" => {
+                options.tail = value("--tail")?
+                    .parse()
+                    .map_err(|err| format!("--tail: {}", err))?
+            }
+            "--bytes" => options.bytes = true,
+            "--check" => options.check = true,
+            "-" => options.trace = None,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.trace = Some(arg),
+        }
+    }
+    Ok(options)
+}
+
+fn debouncer(
+    name: &str,
+    delay: u8,
+) -> Result<Box<dyn Debouncer<{ keymap::ROWS }, { keymap::COLS }>>, String> {
+    Ok(match name {
+        "eager" => Box::new(EagerPressDeferRelease::new(delay)),
+        "defer" => Box::new(SymmetricDefer::new(delay)),
+        "row" => Box::new(PerRow::new(delay)),
+        "key" => Box::new(PerKeyCounter::new(delay)),
+        _ => return Err(format!("unknown debouncer {}", name)),
+    })
+}
+
+fn simulate(options: &Options) -> Result<(), String> {
+    let text = match &options.trace {
+        Some(path) => fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?,
+        None => {
+            let mut text = String::new();
+            io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|err| err.to_string())?;
+            text
+        }
+    };
+    let mut debouncer = debouncer(&options.debounce, options.debounce_ms)?;
+    let mut keyboard: Keyboard<Custom, { keymap::ROWS }, { keymap::COLS }> =
+        Keyboard::new(keymap::LAYERS, keymap::CUSTOM_ACTIONS);
+    if options.check {
+        return trace::check(&text, debouncer.as_mut(), &mut keyboard).map_err(|err| {
+            let name = options.trace.as_deref().unwrap_or("<stdin>");
+            format!("{}: {}", name, err)
+        });
+    }
+
+    let last = Trace::new(&text)
+        .map(|event| event.map(|event| event.time))
+        .try_fold(0, |last, time| time.map(|time| last.max(time)))
+        .map_err(|err| err.to_string())?;
+
+    trace::run(
+        Trace::new(&text),
+        last.saturating_add(options.tail),
+        debouncer.as_mut(),
+        &mut keyboard,
+        |time, report| {
+            let keys = match report.to_string() {
+                keys if keys.is_empty() => "(none)".to_string(),
+                keys => keys,
+            };
+            if options.bytes {
+                let mut line = String::new();
+                trace::write_golden(&mut line, time, report).unwrap();
+                println!("{} # {}", line, keys);
+            } else {
+                println!("t={:<6} {}", time, keys);
+            }
+            for mismatch in report.mismatches() {
+                eprintln!("sim: t={}: {}", time, mismatch);
+            }
+        },
+    )
+    .map_err(|err| err.to_string())
+}
+
+fn main() {
+    let result = parse_options().and_then(|options| simulate(&options));
+    if let Err(err) = result {
+        eprintln!("sim: {}", err);
+        process::exit(1);
+    }
+}
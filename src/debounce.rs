@@ -0,0 +1,328 @@
+//! Debouncing of the matrix state.
+//!
+//! Switch contacts chatter for a few milliseconds when they close or
+//! open, which a scan sees as the key quickly going up and down. A
+//! `Debouncer` is fed every raw `MatrixState` along with the time of the
+//! scan, in ms, and returns the state that is safe to act on.
+//!
+//! All of them wait for `delay` ms of stable input before trusting a
+//! change, and differ in what has to be stable, and for which changes:
+//!
+//! * `SymmetricDefer`: the whole matrix. Cheapest, but typing fast delays
+//!   every key as long as any key is moving.
+//! * `PerRow`: each row on its own.
+//! * `PerKeyCounter`: each key on its own.
+//! * `EagerPressDeferRelease`: presses go through right away, only
+//!   releases wait for their key to be stable. This has the lowest
+//!   latency, but a noise spike on an idle key registers as a tap.
+use crate::matrix::MatrixState;
+
+/// A debouncing algorithm for an `R`×`C` matrix.
+pub trait Debouncer<const R: usize, const C: usize> {
+    /// Feeds the `raw` state scanned at time `now`, in ms, and returns
+    /// the debounced state.
+    fn update(&mut self, raw: &MatrixState<R, C>, now: u32) -> MatrixState<R, C>;
+
+    /// Returns the debounced state, as last returned by `update`.
+    fn state(&self) -> MatrixState<R, C>;
+}
+
+/// Returns the time since `last`, in ms, saturated to `u8::MAX`, and
+/// moves `last` to `now`. The first call returns 0.
+fn elapsed(last: &mut Option<u32>, now: u32) -> u8 {
+    let elapsed = last.map_or(0, |last| now.wrapping_sub(last).min(u8::MAX as u32) as u8);
+    *last = Some(now);
+    elapsed
+}
+
+/// Accepts the raw state once no key changed for `delay` ms.
+#[derive(Debug, Clone)]
+pub struct SymmetricDefer<const R: usize, const C: usize> {
+    delay: u8,
+    last: Option<u32>,
+    stable_for: u8,
+    raw: MatrixState<R, C>,
+    debounced: MatrixState<R, C>,
+}
+
+impl<const R: usize, const C: usize> SymmetricDefer<R, C> {
+    /// Creates a debouncer waiting for `delay` ms of stable input, with
+    /// no key held.
+    pub fn new(delay: u8) -> Self {
+        Self {
+            delay,
+            last: None,
+            stable_for: 0,
+            raw: MatrixState::new(),
+            debounced: MatrixState::new(),
+        }
+    }
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> for SymmetricDefer<R, C> {
+    fn update(&mut self, raw: &MatrixState<R, C>, now: u32) -> MatrixState<R, C> {
+        let elapsed = elapsed(&mut self.last, now);
+        if *raw != self.raw {
+            self.raw = *raw;
+            self.stable_for = 0;
+        } else {
+            self.stable_for = self.stable_for.saturating_add(elapsed);
+        }
+        if self.stable_for >= self.delay {
+            self.debounced = self.raw;
+        }
+        self.debounced
+    }
+
+    fn state(&self) -> MatrixState<R, C> {
+        self.debounced
+    }
+}
+
+/// Accepts the raw state of each row once no key of that row changed
+/// for `delay` ms.
+#[derive(Debug, Clone)]
+pub struct PerRow<const R: usize, const C: usize> {
+    delay: u8,
+    last: Option<u32>,
+    stable_for: [u8; R],
+    raw: MatrixState<R, C>,
+    debounced: MatrixState<R, C>,
+}
+
+impl<const R: usize, const C: usize> PerRow<R, C> {
+    /// Creates a debouncer waiting for `delay` ms of stable input, with
+    /// no key held.
+    pub fn new(delay: u8) -> Self {
+        Self {
+            delay,
+            last: None,
+            stable_for: [0; R],
+            raw: MatrixState::new(),
+            debounced: MatrixState::new(),
+        }
+    }
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> for PerRow<R, C> {
+    fn update(&mut self, raw: &MatrixState<R, C>, now: u32) -> MatrixState<R, C> {
+        let elapsed = elapsed(&mut self.last, now);
+        let mut raw_rows = *self.raw.rows();
+        let mut rows = *self.debounced.rows();
+        for row in 0..R {
+            let stable_for = &mut self.stable_for[row];
+            if raw.rows()[row] != raw_rows[row] {
+                raw_rows[row] = raw.rows()[row];
+                *stable_for = 0;
+            } else {
+                *stable_for = stable_for.saturating_add(elapsed);
+            }
+            if *stable_for >= self.delay {
+                rows[row] = raw_rows[row];
+            }
+        }
+        self.raw = MatrixState::from_rows(raw_rows);
+        self.debounced = MatrixState::from_rows(rows);
+        self.debounced
+    }
+
+    fn state(&self) -> MatrixState<R, C> {
+        self.debounced
+    }
+}
+
+/// Accepts the raw state of each key once it did not change for `delay`
+/// ms, counting the time with a small counter per key.
+#[derive(Debug, Clone)]
+pub struct PerKeyCounter<const R: usize, const C: usize> {
+    delay: u8,
+    last: Option<u32>,
+    stable_for: [[u8; C]; R],
+    raw: MatrixState<R, C>,
+    debounced: MatrixState<R, C>,
+}
+
+impl<const R: usize, const C: usize> PerKeyCounter<R, C> {
+    /// Creates a debouncer waiting for `delay` ms of stable input, with
+    /// no key held.
+    pub fn new(delay: u8) -> Self {
+        Self {
+            delay,
+            last: None,
+            stable_for: [[0; C]; R],
+            raw: MatrixState::new(),
+            debounced: MatrixState::new(),
+        }
+    }
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> for PerKeyCounter<R, C> {
+    fn update(&mut self, raw: &MatrixState<R, C>, now: u32) -> MatrixState<R, C> {
+        let elapsed = elapsed(&mut self.last, now);
+        for row in 0..R {
+            for col in 0..C {
+                let pressed = raw.is_pressed(row, col);
+                let stable_for = &mut self.stable_for[row][col];
+                if pressed != self.raw.is_pressed(row, col) {
+                    self.raw.set(row, col, pressed);
+                    *stable_for = 0;
+                } else {
+                    *stable_for = stable_for.saturating_add(elapsed);
+                }
+                if *stable_for >= self.delay {
+                    self.debounced.set(row, col, pressed);
+                }
+            }
+        }
+        self.debounced
+    }
+
+    fn state(&self) -> MatrixState<R, C> {
+        self.debounced
+    }
+}
+
+/// Accepts key presses as soon as they are scanned, and releases once
+/// the key stayed released for `delay` ms.
+#[derive(Debug, Clone)]
+pub struct EagerPressDeferRelease<const R: usize, const C: usize> {
+    delay: u8,
+    last: Option<u32>,
+    stable_for: [[u8; C]; R],
+    raw: MatrixState<R, C>,
+    debounced: MatrixState<R, C>,
+}
+
+impl<const R: usize, const C: usize> EagerPressDeferRelease<R, C> {
+    /// Creates a debouncer waiting for `delay` ms of stable input before
+    /// a release, with no key held.
+    pub fn new(delay: u8) -> Self {
+        Self {
+            delay,
+            last: None,
+            stable_for: [[0; C]; R],
+            raw: MatrixState::new(),
+            debounced: MatrixState::new(),
+        }
+    }
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> for EagerPressDeferRelease<R, C> {
+    fn update(&mut self, raw: &MatrixState<R, C>, now: u32) -> MatrixState<R, C> {
+        let elapsed = elapsed(&mut self.last, now);
+        for row in 0..R {
+            for col in 0..C {
+                let pressed = raw.is_pressed(row, col);
+                let stable_for = &mut self.stable_for[row][col];
+                if pressed != self.raw.is_pressed(row, col) {
+                    self.raw.set(row, col, pressed);
+                    *stable_for = 0;
+                } else {
+                    *stable_for = stable_for.saturating_add(elapsed);
+                }
+                if pressed || *stable_for >= self.delay {
+                    self.debounced.set(row, col, pressed);
+                }
+            }
+        }
+        self.debounced
+    }
+
+    fn state(&self) -> MatrixState<R, C> {
+        self.debounced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Trace;
+
+    /// The chatter of `traces/chatter.golden`: switch 0,1 bouncing for
+    /// 4 ms on press at t=0, and for 2 ms on release at t=100.
+    const CHATTER: &str = include_str!("../traces/chatter.golden");
+
+    /// Runs the trace before the `---` line of `golden` through
+    /// `debouncer`, one scan per ms, and returns the time and state of
+    /// every change of switch 0,1 it lets through.
+    fn changes(golden: &str, debouncer: impl Debouncer<1, 3>) -> Vec<(u32, bool)> {
+        changes_in_rows(golden, debouncer)
+    }
+
+    /// Like `changes`, on a matrix of `R` rows.
+    fn changes_in_rows<const R: usize>(
+        golden: &str,
+        mut debouncer: impl Debouncer<R, 3>,
+    ) -> Vec<(u32, bool)> {
+        let (trace, _) = golden.split_once("---").unwrap();
+        let mut events = Trace::new(trace).map(Result::unwrap).peekable();
+        let mut raw = MatrixState::new();
+        let mut pressed = false;
+        let mut changes = Vec::new();
+        for now in 0..200 {
+            while let Some(event) = events.next_if(|event| event.time <= now) {
+                raw.set(event.row, event.col, event.pressed);
+            }
+            let debounced = debouncer.update(&raw, now);
+            assert_eq!(debounced, debouncer.state());
+            if debounced.is_pressed(0, 1) != pressed {
+                pressed = !pressed;
+                changes.push((now, pressed));
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn chatter_defers_both_edges() {
+        // the press is stable from t=4, the release from t=102
+        let expected = [(9, true), (107, false)];
+        assert_eq!(changes(CHATTER, SymmetricDefer::new(5)), expected);
+        assert_eq!(changes(CHATTER, PerRow::new(5)), expected);
+        assert_eq!(changes(CHATTER, PerKeyCounter::new(5)), expected);
+    }
+
+    #[test]
+    fn chatter_eager_press() {
+        let expected = [(0, true), (107, false)];
+        assert_eq!(changes(CHATTER, EagerPressDeferRelease::new(5)), expected);
+    }
+
+    #[test]
+    fn noise_spike() {
+        let spike = "t=50 press 0,1\nt=51 release 0,1\n---";
+        assert_eq!(changes(spike, SymmetricDefer::new(5)), []);
+        assert_eq!(changes(spike, PerRow::new(5)), []);
+        assert_eq!(changes(spike, PerKeyCounter::new(5)), []);
+        assert_eq!(
+            changes(spike, EagerPressDeferRelease::new(5)),
+            [(50, true), (56, false)]
+        );
+    }
+
+    #[test]
+    fn only_per_key_debouncers_ignore_other_keys() {
+        // switch 0,2 keeps moving while 0,1 is pressed once
+        let typing =
+            "t=0 press 0,1; t=2 press 0,2; t=4 release 0,2; t=6 press 0,2; t=8 release 0,2\n---";
+        assert_eq!(changes(typing, SymmetricDefer::new(5)), [(13, true)]);
+        assert_eq!(changes(typing, PerRow::new(5)), [(13, true)]);
+        assert_eq!(changes(typing, PerKeyCounter::new(5)), [(5, true)]);
+    }
+
+    #[test]
+    fn per_row_ignores_other_rows() {
+        // switch 1,2 keeps moving on the second row while 0,1 is pressed
+        let typing =
+            "t=0 press 0,1; t=2 press 1,2; t=4 release 1,2; t=6 press 1,2; t=8 release 1,2\n---";
+        assert_eq!(
+            changes_in_rows(typing, SymmetricDefer::<2, 3>::new(5)),
+            [(13, true)]
+        );
+        assert_eq!(changes_in_rows(typing, PerRow::<2, 3>::new(5)), [(5, true)]);
+        assert_eq!(
+            changes_in_rows(typing, PerKeyCounter::<2, 3>::new(5)),
+            [(5, true)]
+        );
+    }
+}
@@ -0,0 +1,601 @@
+//! HID report descriptor parsing and validation.
+//!
+//! `parse` walks the items of a report descriptor (section 6.2.2 of the
+//! HID 1.11 spec) the way a host does, keeping track of the global and
+//! local state, and returns the size of every report it describes, by
+//! Report ID. It also catches the mistakes that hosts either reject or
+//! silently misread:
+//!
+//! * a Collection without End Collection, or the other way around;
+//! * a data field whose Logical Minimum and Maximum do not fit in its
+//!   Report Size, like `Logical Maximum (255)` written as `0x25, 0xFF`,
+//!   which is -1 as the item data is signed;
+//! * a Usage Minimum above the Usage Maximum, or a Variable field with
+//!   a Report Count different from the number of usages in its range;
+//! * Report IDs used for some reports but not others;
+//! * reports that are not a whole number of bytes.
+//!
+//! `parse` is a `const fn`, so `hid` checks each descriptor it ships at
+//! compile time, and can use the sizes in its own compile time checks.
+use core::fmt;
+
+/// Most Report IDs a descriptor can use for `parse` to handle it.
+pub const MAX_REPORTS: usize = 8;
+
+/// The kinds of items, from bits 2 and 3 of their prefix.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ItemType {
+    Main,
+    Global,
+    Local,
+    Reserved,
+}
+
+/// A short item of a report descriptor.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Item {
+    /// Offset of the item prefix in the descriptor.
+    pub offset: usize,
+    pub kind: ItemType,
+    /// The item tag, from the upper 4 bits of its prefix.
+    pub tag: u8,
+    /// Size of the item data, 0, 1, 2 or 4 bytes.
+    pub size: usize,
+    /// The item data, little endian, zero extended.
+    pub data: u32,
+}
+
+impl Item {
+    /// Returns the item data, sign extended from its size.
+    pub const fn signed(&self) -> i32 {
+        match self.size {
+            1 => self.data as u8 as i8 as i32,
+            2 => self.data as u16 as i16 as i32,
+            _ => self.data as i32,
+        }
+    }
+}
+
+// Main item tags
+const INPUT: u8 = 0x8;
+const OUTPUT: u8 = 0x9;
+const COLLECTION: u8 = 0xA;
+const FEATURE: u8 = 0xB;
+const END_COLLECTION: u8 = 0xC;
+
+// Global item tags
+const LOGICAL_MINIMUM: u8 = 0x1;
+const LOGICAL_MAXIMUM: u8 = 0x2;
+const REPORT_SIZE: u8 = 0x7;
+const REPORT_ID: u8 = 0x8;
+const REPORT_COUNT: u8 = 0x9;
+const PUSH: u8 = 0xA;
+const POP: u8 = 0xB;
+
+// Local item tags
+const USAGE_MINIMUM: u8 = 0x1;
+const USAGE_MAXIMUM: u8 = 0x2;
+
+/// Long items start with this prefix, and are skipped.
+const LONG_ITEM: u8 = 0xFE;
+
+/// Decodes the item at `offset` of `descriptor`, returning it and the
+/// offset of the next one, or `None` for a long item.
+const fn item(descriptor: &[u8], offset: usize) -> Result<(Option<Item>, usize), DescriptorError> {
+    let prefix = descriptor[offset];
+    if prefix == LONG_ITEM {
+        if offset + 2 >= descriptor.len() {
+            return Err(DescriptorError::Truncated { offset });
+        }
+        let next = offset + 3 + descriptor[offset + 1] as usize;
+        if next > descriptor.len() {
+            return Err(DescriptorError::Truncated { offset });
+        }
+        return Ok((None, next));
+    }
+
+    let size = match prefix & 0x03 {
+        3 => 4,
+        n => n as usize,
+    };
+    if offset + 1 + size > descriptor.len() {
+        return Err(DescriptorError::Truncated { offset });
+    }
+    let mut data = 0;
+    let mut i = 0;
+    while i < size {
+        data |= (descriptor[offset + 1 + i] as u32) << (8 * i);
+        i += 1;
+    }
+    let kind = match (prefix >> 2) & 0x03 {
+        0 => ItemType::Main,
+        1 => ItemType::Global,
+        2 => ItemType::Local,
+        _ => ItemType::Reserved,
+    };
+    let item = Item {
+        offset,
+        kind,
+        tag: prefix >> 4,
+        size,
+        data,
+    };
+    Ok((Some(item), offset + 1 + size))
+}
+
+/// The short items of a report descriptor, in order.
+#[derive(Debug, Clone)]
+pub struct Items<'a> {
+    descriptor: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Items<'a> {
+    pub fn new(descriptor: &'a [u8]) -> Self {
+        Self {
+            descriptor,
+            offset: 0,
+        }
+    }
+}
+
+impl Iterator for Items<'_> {
+    type Item = Result<Item, DescriptorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.offset < self.descriptor.len() {
+            match item(self.descriptor, self.offset) {
+                Ok((item, next)) => {
+                    self.offset = next;
+                    if item.is_some() {
+                        return item.map(Ok);
+                    }
+                }
+                Err(err) => {
+                    self.offset = self.descriptor.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A mistake found by `parse`, with the offset of the item at fault.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DescriptorError {
+    /// The item runs past the end of the descriptor.
+    Truncated { offset: usize },
+    /// An End Collection without a Collection, or a Collection left open
+    /// at the end of the descriptor, at `offset` == its length.
+    UnbalancedCollection { offset: usize },
+    /// A main item without a Report Size or Report Count before it.
+    MissingGlobal { offset: usize },
+    /// A data main item whose logical range is empty, or does not fit in
+    /// its Report Size.
+    LogicalRange { offset: usize },
+    /// A Usage Maximum below its Usage Minimum, or a Variable data main
+    /// item with a Report Count other than its number of usages.
+    UsageRange { offset: usize },
+    /// A Report ID of 0, or main items both with and without Report ID.
+    ReportId { offset: usize },
+    /// More than `MAX_REPORTS` Report IDs.
+    TooManyReports { offset: usize },
+    /// A Pop without Push, or more Push than `parse` can keep.
+    PushPop { offset: usize },
+    /// A report that is not a whole number of bytes.
+    NotByteAligned { id: u8 },
+}
+
+impl DescriptorError {
+    /// Describes the mistake, without its offset.
+    pub const fn message(&self) -> &'static str {
+        match self {
+            DescriptorError::Truncated { .. } => "item runs past the end of the descriptor",
+            DescriptorError::UnbalancedCollection { .. } => {
+                "unbalanced Collection / End Collection"
+            }
+            DescriptorError::MissingGlobal { .. } => {
+                "main item without Report Size or Report Count"
+            }
+            DescriptorError::LogicalRange { .. } => {
+                "logical range is empty or does not fit in Report Size"
+            }
+            DescriptorError::UsageRange { .. } => {
+                "Usage Maximum below Usage Minimum, or Report Count differs from usage range"
+            }
+            DescriptorError::ReportId { .. } => {
+                "Report ID 0, or reports both with and without Report ID"
+            }
+            DescriptorError::TooManyReports { .. } => "too many Report IDs",
+            DescriptorError::PushPop { .. } => "unbalanced Push / Pop",
+            DescriptorError::NotByteAligned { .. } => "report is not a whole number of bytes",
+        }
+    }
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::NotByteAligned { id } => {
+                write!(f, "report {}: {}", id, self.message())
+            }
+            DescriptorError::Truncated { offset }
+            | DescriptorError::UnbalancedCollection { offset }
+            | DescriptorError::MissingGlobal { offset }
+            | DescriptorError::LogicalRange { offset }
+            | DescriptorError::UsageRange { offset }
+            | DescriptorError::ReportId { offset }
+            | DescriptorError::TooManyReports { offset }
+            | DescriptorError::PushPop { offset } => {
+                write!(f, "offset {}: {}", offset, self.message())
+            }
+        }
+    }
+}
+
+/// The sizes in bits of the reports with a given Report ID, without the
+/// Report ID byte. The ID is 0 for descriptors without Report IDs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReportSizes {
+    pub id: u8,
+    pub input: usize,
+    pub output: usize,
+    pub feature: usize,
+}
+
+impl ReportSizes {
+    const fn new(id: u8) -> Self {
+        Self {
+            id,
+            input: 0,
+            output: 0,
+            feature: 0,
+        }
+    }
+}
+
+/// The reports of a descriptor, as found by `parse`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Summary {
+    reports: [ReportSizes; MAX_REPORTS],
+    len: usize,
+}
+
+impl Summary {
+    /// Returns the sizes of every report, in the order their Report ID
+    /// first appears.
+    pub const fn reports(&self) -> &[ReportSizes] {
+        self.reports.split_at(self.len).0
+    }
+
+    /// Returns the sizes of the reports with the given Report ID.
+    pub const fn report(&self, id: u8) -> Option<ReportSizes> {
+        let mut i = 0;
+        while i < self.len {
+            if self.reports[i].id == id {
+                return Some(self.reports[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Returns the length in bytes of the input report with the given
+    /// Report ID, without the Report ID byte, or 0 if there is none.
+    pub const fn input_len(&self, id: u8) -> usize {
+        match self.report(id) {
+            Some(report) => report.input / 8,
+            None => 0,
+        }
+    }
+
+    /// Returns the length in bytes of the output report with the given
+    /// Report ID, without the Report ID byte, or 0 if there is none.
+    pub const fn output_len(&self, id: u8) -> usize {
+        match self.report(id) {
+            Some(report) => report.output / 8,
+            None => 0,
+        }
+    }
+
+    /// Returns the length in bytes of the feature report with the given
+    /// Report ID, without the Report ID byte, or 0 if there is none.
+    pub const fn feature_len(&self, id: u8) -> usize {
+        match self.report(id) {
+            Some(report) => report.feature / 8,
+            None => 0,
+        }
+    }
+}
+
+/// The global items `parse` keeps track of.
+#[derive(Clone, Copy)]
+struct Globals {
+    logical_min: i32,
+    logical_max: i32,
+    report_size: Option<u32>,
+    report_count: Option<u32>,
+    report_id: u8,
+}
+
+impl Globals {
+    const NEW: Self = Self {
+        logical_min: 0,
+        logical_max: 0,
+        report_size: None,
+        report_count: None,
+        report_id: 0,
+    };
+}
+
+/// Most nested Push items `parse` can keep.
+const MAX_PUSH: usize = 4;
+
+/// Returns `true` if every value of the logical range fits in `bits`.
+const fn fits(min: i32, max: i32, bits: u32) -> bool {
+    if min > max || bits == 0 || bits > 32 {
+        return false;
+    }
+    let (min, max) = (min as i64, max as i64);
+    if min < 0 {
+        min >= -(1 << (bits - 1)) && max < (1 << (bits - 1))
+    } else {
+        max < (1 << bits)
+    }
+}
+
+/// Parses and checks `descriptor`, returning the sizes of its reports.
+pub const fn parse(descriptor: &[u8]) -> Result<Summary, DescriptorError> {
+    let mut summary = Summary {
+        reports: [ReportSizes::new(0); MAX_REPORTS],
+        len: 0,
+    };
+    let mut globals = Globals::NEW;
+    let mut pushed = [Globals::NEW; MAX_PUSH];
+    let mut depth = 0;
+    let mut collections = 0;
+    let (mut usage_minimum, mut usage_maximum) = (None, None);
+    let (mut with_id, mut without_id) = (false, false);
+
+    let mut offset = 0;
+    while offset < descriptor.len() {
+        let item = match item(descriptor, offset) {
+            Ok((Some(item), next)) => {
+                offset = next;
+                item
+            }
+            Ok((None, next)) => {
+                offset = next;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let at = item.offset;
+
+        match (item.kind, item.tag) {
+            (ItemType::Main, INPUT | OUTPUT | FEATURE) => {
+                let (size, count) = match (globals.report_size, globals.report_count) {
+                    (Some(size), Some(count)) => (size, count),
+                    _ => return Err(DescriptorError::MissingGlobal { offset: at }),
+                };
+                let constant = item.data & 0x01 != 0;
+                let variable = item.data & 0x02 != 0;
+                if !constant && !fits(globals.logical_min, globals.logical_max, size) {
+                    return Err(DescriptorError::LogicalRange { offset: at });
+                }
+                if let (false, true, Some(minimum), Some(maximum)) =
+                    (constant, variable, usage_minimum, usage_maximum)
+                {
+                    if count != maximum - minimum + 1 {
+                        return Err(DescriptorError::UsageRange { offset: at });
+                    }
+                }
+                if globals.report_id == 0 {
+                    without_id = true;
+                }
+                if with_id && without_id {
+                    return Err(DescriptorError::ReportId { offset: at });
+                }
+
+                let mut i = 0;
+                while i < summary.len && summary.reports[i].id != globals.report_id {
+                    i += 1;
+                }
+                if i == summary.len {
+                    if i == MAX_REPORTS {
+                        return Err(DescriptorError::TooManyReports { offset: at });
+                    }
+                    summary.reports[i] = ReportSizes::new(globals.report_id);
+                    summary.len += 1;
+                }
+                let bits = (size * count) as usize;
+                match item.tag {
+                    INPUT => summary.reports[i].input += bits,
+                    OUTPUT => summary.reports[i].output += bits,
+                    _ => summary.reports[i].feature += bits,
+                }
+            }
+            (ItemType::Main, COLLECTION) => collections += 1,
+            (ItemType::Main, END_COLLECTION) => {
+                if collections == 0 {
+                    return Err(DescriptorError::UnbalancedCollection { offset: at });
+                }
+                collections -= 1;
+            }
+            (ItemType::Global, LOGICAL_MINIMUM) => globals.logical_min = item.signed(),
+            (ItemType::Global, LOGICAL_MAXIMUM) => globals.logical_max = item.signed(),
+            (ItemType::Global, REPORT_SIZE) => globals.report_size = Some(item.data),
+            (ItemType::Global, REPORT_COUNT) => globals.report_count = Some(item.data),
+            (ItemType::Global, REPORT_ID) => {
+                if item.data == 0 || item.data > u8::MAX as u32 || without_id {
+                    return Err(DescriptorError::ReportId { offset: at });
+                }
+                globals.report_id = item.data as u8;
+                with_id = true;
+            }
+            (ItemType::Global, PUSH) => {
+                if depth == MAX_PUSH {
+                    return Err(DescriptorError::PushPop { offset: at });
+                }
+                pushed[depth] = globals;
+                depth += 1;
+            }
+            (ItemType::Global, POP) => {
+                if depth == 0 {
+                    return Err(DescriptorError::PushPop { offset: at });
+                }
+                depth -= 1;
+                globals = pushed[depth];
+            }
+            (ItemType::Local, USAGE_MINIMUM) => usage_minimum = Some(item.data),
+            (ItemType::Local, USAGE_MAXIMUM) => {
+                if let Some(minimum) = usage_minimum {
+                    if item.data < minimum {
+                        return Err(DescriptorError::UsageRange { offset: at });
+                    }
+                }
+                usage_maximum = Some(item.data);
+            }
+            _ => (),
+        }
+        // local items only apply to the next main item
+        if let ItemType::Main = item.kind {
+            (usage_minimum, usage_maximum) = (None, None);
+        }
+    }
+
+    if collections != 0 {
+        return Err(DescriptorError::UnbalancedCollection {
+            offset: descriptor.len(),
+        });
+    }
+    let mut i = 0;
+    while i < summary.len {
+        let report = summary.reports[i];
+        if !(report.input.is_multiple_of(8)
+            && report.output.is_multiple_of(8)
+            && report.feature.is_multiple_of(8))
+        {
+            return Err(DescriptorError::NotByteAligned { id: report.id });
+        }
+        i += 1;
+    }
+    Ok(summary)
+}
+
+/// Parses `descriptor` like `parse`, failing compilation when used in a
+/// constant and the descriptor has a mistake.
+pub const fn expect_valid(descriptor: &[u8]) -> Summary {
+    match parse(descriptor) {
+        Ok(summary) => summary,
+        Err(err) => panic!("{}", err.message()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Collection (Application) and End Collection
+    const OPEN: [u8; 2] = [0xA1, 0x01];
+    const CLOSE: [u8; 1] = [0xC0];
+
+    /// Logical 0 to 1, Report Size 1, Report Count 8, Input (Data, Var).
+    const BITS: [u8; 10] = [0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02];
+
+    fn descriptor(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn valid() {
+        let summary = parse(&descriptor(&[&OPEN, &BITS, &CLOSE])).unwrap();
+        assert_eq!(summary.input_len(0), 1);
+        assert_eq!(summary.output_len(0), 0);
+        assert_eq!(summary.reports().len(), 1);
+    }
+
+    #[test]
+    fn unbalanced_collection() {
+        let missing_end = descriptor(&[&OPEN, &BITS]);
+        assert_eq!(
+            parse(&missing_end),
+            Err(DescriptorError::UnbalancedCollection { offset: 12 })
+        );
+        let extra_end = descriptor(&[&OPEN, &BITS, &CLOSE, &CLOSE]);
+        assert_eq!(
+            parse(&extra_end),
+            Err(DescriptorError::UnbalancedCollection { offset: 13 })
+        );
+    }
+
+    #[test]
+    fn logical_range() {
+        // Logical Maximum (255) written with 1 byte of data is -1
+        let byte = |max: &[u8]| {
+            let fields: &[u8] = &[0x15, 0x00, 0x75, 0x08, 0x95, 0x01, 0x81, 0x00];
+            descriptor(&[&OPEN, max, fields, &CLOSE])
+        };
+        assert_eq!(
+            parse(&byte(&[0x25, 0xFF])),
+            Err(DescriptorError::LogicalRange { offset: 10 })
+        );
+        assert_eq!(parse(&byte(&[0x26, 0xFF, 0x00])).unwrap().input_len(0), 1);
+        // 0 to 2 does not fit in 1 bit
+        let mut too_wide = BITS;
+        too_wide[3] = 2;
+        assert_eq!(
+            parse(&descriptor(&[&OPEN, &too_wide, &CLOSE])),
+            Err(DescriptorError::LogicalRange { offset: 10 })
+        );
+    }
+
+    #[test]
+    fn usage_range() {
+        // 8 usages from LCtrl, for 7 bits
+        let mut fields = BITS;
+        fields[7] = 7;
+        let usages: &[u8] = &[0x19, 0xE0, 0x29, 0xE7];
+        let count = descriptor(&[&OPEN, usages, &fields, &CLOSE]);
+        assert_eq!(
+            parse(&count),
+            Err(DescriptorError::UsageRange { offset: 14 })
+        );
+        // Usage Maximum below Usage Minimum
+        let reversed: &[u8] = &[0x19, 0xE7, 0x29, 0xE0];
+        assert_eq!(
+            parse(&descriptor(&[&OPEN, reversed, &BITS, &CLOSE])),
+            Err(DescriptorError::UsageRange { offset: 4 })
+        );
+    }
+
+    #[test]
+    fn mixed_report_id() {
+        let mixed = descriptor(&[&OPEN, &BITS, &[0x85, 0x01], &BITS, &CLOSE]);
+        assert_eq!(parse(&mixed), Err(DescriptorError::ReportId { offset: 12 }));
+        let zero = descriptor(&[&OPEN, &[0x85, 0x00], &BITS, &CLOSE]);
+        assert_eq!(parse(&zero), Err(DescriptorError::ReportId { offset: 2 }));
+        let ids = descriptor(&[
+            &OPEN,
+            &[0x85, 0x01],
+            &BITS,
+            &[0x85, 0x02],
+            &BITS,
+            &BITS,
+            &CLOSE,
+        ]);
+        let summary = parse(&ids).unwrap();
+        assert_eq!((summary.input_len(1), summary.input_len(2)), (1, 2));
+    }
+
+    #[test]
+    fn not_byte_aligned() {
+        let mut fields = BITS;
+        fields[7] = 3;
+        assert_eq!(
+            parse(&descriptor(&[&OPEN, &[0x85, 0x04], &fields, &CLOSE])),
+            Err(DescriptorError::NotByteAligned { id: 4 })
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Keys wired straight to their own input pin.
+//!
+//! Small macropads skip the matrix: each switch connects a GPIO to
+//! ground, read with a pull-up, or to the supply, read with a pull-down.
+//! `DirectPins` reads them into a `MatrixState` with one pin per key
+//! position, so they go through the same debouncing and layout as a
+//! matrix. No strobing means no settle delay and no ghosting.
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::InputPin;
+
+use crate::matrix::{KeySource, MatrixState};
+
+/// The level an input pin reads while its key is held.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ActiveLevel {
+    /// The switch pulls the pin to ground, against a pull-up.
+    Low,
+    /// The switch pulls the pin to the supply, against a pull-down.
+    High,
+}
+
+/// The keys of an `R`×`C` layout, each read from its own input pin.
+/// Like for `Matrix`, every pin must be the same type.
+pub struct DirectPins<I, const R: usize, const C: usize> {
+    pins: [[I; C]; R],
+    active: ActiveLevel,
+}
+
+impl<I, E, const R: usize, const C: usize> DirectPins<I, R, C>
+where
+    I: InputPin<Error = E>,
+{
+    /// Creates a reader for `pins`, indexed by row then column like the
+    /// layers of the keymap, whose keys are held at the `active` level.
+    pub fn new(pins: [[I; C]; R], active: ActiveLevel) -> Self {
+        Self { pins, active }
+    }
+
+    /// Reads which keys are held.
+    pub fn read(&self) -> Result<MatrixState<R, C>, E> {
+        let mut state = MatrixState::new();
+        for (row, pins) in self.pins.iter().enumerate() {
+            for (col, pin) in pins.iter().enumerate() {
+                let pressed = match self.active {
+                    ActiveLevel::Low => pin.is_low()?,
+                    ActiveLevel::High => pin.is_high()?,
+                };
+                state.set(row, col, pressed);
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl<I, E, const R: usize, const C: usize> KeySource<R, C> for DirectPins<I, R, C>
+where
+    I: InputPin<Error = E>,
+{
+    type Error = E;
+
+    fn scan<D: DelayUs<u32>>(&mut self, _: &mut D) -> Result<MatrixState<R, C>, E> {
+        self.read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pin reading `Ok(high)`, or `Err(())` for a broken one.
+    struct Pin(Result<bool, ()>);
+
+    impl InputPin for Pin {
+        type Error = ();
+
+        fn is_high(&self) -> Result<bool, ()> {
+            self.0
+        }
+
+        fn is_low(&self) -> Result<bool, ()> {
+            self.0.map(|high| !high)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _: u32) {
+            unreachable!("direct pins need no settle delay");
+        }
+    }
+
+    /// The pins of a 2×3 layout, reading high where `high` has a 1 bit,
+    /// bit `c` of row `r` being the pin of column `c`.
+    fn pins(high: [u32; 2]) -> [[Pin; 3]; 2] {
+        high.map(|row| core::array::from_fn(|col| Pin(Ok(row & (1 << col) != 0))))
+    }
+
+    #[test]
+    fn active_low_reads_low_pins() {
+        let mut keys = DirectPins::new(pins([0b110, 0b011]), ActiveLevel::Low);
+        let state = keys.scan(&mut NoDelay).unwrap();
+        assert_eq!(state, MatrixState::from_rows([0b001, 0b100]));
+    }
+
+    #[test]
+    fn active_high_reads_high_pins() {
+        let mut keys = DirectPins::new(pins([0b110, 0b011]), ActiveLevel::High);
+        let state = keys.scan(&mut NoDelay).unwrap();
+        assert_eq!(state, MatrixState::from_rows([0b110, 0b011]));
+    }
+
+    #[test]
+    fn pin_order_gives_columns() {
+        for row in 0..2 {
+            for col in 0..3 {
+                let mut high = [0; 2];
+                high[row] = 1 << col;
+                let keys = DirectPins::new(pins(high), ActiveLevel::High);
+                let state = keys.read().unwrap();
+                assert_eq!(state.pressed().collect::<Vec<_>>(), [(row, col)]);
+            }
+        }
+    }
+
+    #[test]
+    fn pin_error_is_returned() {
+        let mut pins = pins([0, 0]);
+        pins[1][2] = Pin(Err(()));
+        let keys = DirectPins::new(pins, ActiveLevel::Low);
+        assert_eq!(keys.read(), Err(()));
+    }
+}
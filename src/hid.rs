@@ -1,58 +1,99 @@
 extern crate keyberon;
+use core::fmt;
+
+use bitflags::bitflags;
 use keyberon::key_code::KeyCode;
 
+use crate::descriptor;
+
 /// This is our custom report descriptor. It defines a report with a packed byte
 /// for the modifier keys, a single reserved byte of 0's, then the 6-byte array
 /// of keycodes used for boot-compliant drivers, followed by a bitpacked
-pub const NKRO_REPORT_DESCRIPTOR: &[u8] = &[
-    0x05, 0x01, // Usage Page (Generic Desktop),
-    0x09, 0x06, // Usage (Keyboard),
-    0xA1, 0x01, // Collection (Application),
-    0x85, 0x04, //   Report ID (4),
+/// array of every other key on the keyboard page. It also defines the
+/// single byte output report the host uses to set the keyboard LEDs,
+/// which is the same as the BOOT protocol one (see `HostLeds`).
+///
+/// It deliberately has no Report ID: a report ID would be sent as the first
+/// byte of every report, shifting the boot layout out of place.
+pub const NKRO_REPORT_DESCRIPTOR: &[u8] = &NKRO_DESCRIPTOR.build::<{ NKRO_DESCRIPTOR.len() }>();
+
+const NKRO_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x06) // Keyboard
+    .collection(Collection::Application)
     // hybrid of modifiers
-    0x75, 0x01, //   Report Size (1),
-    0x95, 0x08, //   Report Count (8),
-    0x05, 0x07, //   Usage Page (Key Codes),
-    0x19, 0xE0, //   Usage Minimum (224),
-    0x29, 0xE7, //   Usage Maximum (231),
-    0x15, 0x00, //   Logical Minimum (0),
-    0x25, 0x01, //   Logical Maximum (1),
-    0x81, 0x02, //   Input (Data, Variable, Absolute), ;Modifier byte
+    .report_size(1)
+    .report_count(8)
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0xE0)
+    .usage_maximum(0xE7)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .input(MainFlags::VARIABLE)
     // Padding / fake boot keyboard
-    0x95, 0x38, //   Report Count (56),
-    0x75, 0x01, //   Report Size (1),
-    0x81, 0x01, //   Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+    .report_count(56)
+    .report_size(1)
+    .input(MainFlags::CONSTANT)
+    // LED output report
+    .report_count(5)
+    .report_size(1)
+    .usage_page(UsagePage::Leds)
+    .usage_minimum(0x01) // Num Lock
+    .usage_maximum(0x05) // Kana
+    .output(MainFlags::VARIABLE)
+    .report_count(1)
+    .report_size(3)
+    .output(MainFlags::CONSTANT) // LED report padding
     // hybrid of keys
-    0x95, 0x68, //  Report Count (104),
-    0x75, 0x01, //  Report Size (1),
-    0x15, 0x00, //  Logical Minimum (0),
-    0x25, 0x01, //  Logical Maximum(1),
-    0x05, 0x07, //  Usage Page (Key Codes),
-    0x19, 0x00, //  Usage Minimum (0),
-    0x29, 0x68, //   Usage Maximum (104),
-    0x81, 0x02, //  Input (Data, Variable, Absolute),
-    0xc0, //  End Collection
-];
+    .report_count(224)
+    .report_size(1)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0x00)
+    .usage_maximum(0xDF)
+    .input(MainFlags::VARIABLE)
+    .end_collection();
 
 /// Struct representing our custom report descriptor.
 /// The first byte is a bitfield of modifiers, followed by a
-/// padding byte, and 6 bytes for BOOT protocol scancodes. A BIOS/UEFI
-/// system will either properly parse our report descriptor and treat
-/// the 'boot' scancode array as padding, or it will ignore our report
-/// descriptor and read the first 8 bytes of our report as if it follows
-/// the BOOT protocol. This allows us to have NKRO behavior once an OS
-/// boots with a full USB HID implementation, but still be able to use
-/// the keyboard during boot for BIOS/UEFI systems that do not properly
-/// or fully implement the HID specification.
+/// padding byte, and 6 bytes for BOOT protocol scancodes. Since the
+/// report carries no Report ID, those first 8 bytes are laid out exactly
+/// like a BOOT protocol report. A BIOS/UEFI system will either properly
+/// parse our report descriptor and treat the 'boot' scancode array as
+/// padding, or it will ignore our report descriptor and read the first
+/// 8 bytes of our report as if it follows the BOOT protocol. This allows
+/// us to have NKRO behavior once an OS boots with a full USB HID
+/// implementation, but still be able to use the keyboard during boot for
+/// BIOS/UEFI systems that do not properly or fully implement the HID
+/// specification.
 ///
 /// If HID is properly implemented (like in linux or OSX), then the host
 /// will skip the reserved padding and boot array, and only use our
-/// NKRO bitmap. This bitmap represents the first 104 keys defined by
-/// the HID usage table for keyboards. This is enough for most people in
-/// the US, and definitely enough for my personal use.
+/// NKRO bitmap. This bitmap covers every non-modifier usage of the HID
+/// keyboard page (0x00 - 0xDF), so F13-F24, the International and Lang
+/// keys used by JIS and Korean layouts, and the rest of the page all
+/// get their own bit. The modifiers (0xE0 - 0xE7) live in the first byte.
 #[repr(C)]
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
-pub struct NKROReport([u8; 21]);
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NKROReport([u8; NKRO_REPORT_LEN]);
+
+/// Length in bytes of an `NKROReport`.
+pub const NKRO_REPORT_LEN: usize = 36;
+
+// The descriptor and the report must agree on the report length, or the
+// host will parse every field past the mismatch at the wrong offset.
+const _: () = {
+    let reports = descriptor::expect_valid(NKRO_REPORT_DESCRIPTOR);
+    assert!(reports.input_len(0) == NKRO_REPORT_LEN);
+    assert!(reports.output_len(0) == 1);
+};
+
+impl Default for NKROReport {
+    fn default() -> Self {
+        Self([0; NKRO_REPORT_LEN])
+    }
+}
 
 impl core::iter::FromIterator<KeyCode> for NKROReport {
     fn from_iter<T>(iter: T) -> Self
@@ -67,6 +108,32 @@ impl core::iter::FromIterator<KeyCode> for NKROReport {
     }
 }
 
+/// Number of keycodes covered by the NKRO bitmap, starting from usage 0.
+const BITMAP_KEYS: u8 = 0xE0;
+
+/// Offset of the NKRO bitmap within the report.
+const BITMAP_OFFSET: usize = 8;
+
+/// Returns the byte index and bit mask of the usage `code` within the
+/// NKRO bitmap, or `None` if it has no bit in it.
+fn bitmap_bit(code: u8) -> Option<(usize, u8)> {
+    match code {
+        0..=3 => None,
+        code if code < BITMAP_KEYS => Some((BITMAP_OFFSET + code as usize / 8, 1 << (code % 8))),
+        _ => None,
+    }
+}
+
+/// Converts a raw HID usage back into a `KeyCode`, if keyberon defines one.
+fn key_code(code: u8) -> Option<KeyCode> {
+    match code {
+        // Safety: `KeyCode` is `repr(u8)` and defines every discriminant in
+        // these two ranges.
+        0x00..=0xA4 | 0xE0..=0xFB => Some(unsafe { core::mem::transmute::<u8, KeyCode>(code) }),
+        _ => None,
+    }
+}
+
 impl NKROReport {
     /// Returns the report as a byte slice
     pub fn as_bytes(&self) -> &[u8] {
@@ -74,74 +141,174 @@ impl NKROReport {
     }
 
     /// Add the given key code to the report. This will mainly
-    /// modify the last 13 bytes of the NKROReport, which is our bitmap
-    /// of keycodes (From 0 - 104 in the HID Keyboard usage table),
+    /// modify the last 28 bytes of the NKROReport, which is our bitmap
+    /// of keycodes (From 0 - 223 in the HID Keyboard usage table),
     /// however, it will also update the modifer bitmap, and the BOOT
     /// protocol array that is within the first 8 bytes of the report.
     /// This is so that the keyboard still works during boot with buggy
     /// BIOS/UEFI implementations.
+    ///
+    /// Pressing a key that is already in the report does nothing, and
+    /// keyberon's unofficial media codes (0xE8 and up) are ignored since
+    /// they are not usages of the keyboard page.
     pub fn pressed(&mut self, kc: KeyCode) {
         use KeyCode::*;
         match kc {
             No => (),
             ErrorRollOver | PostFail | ErrorUndefined => self.set_all(kc),
             kc if kc.is_modifier() => self.0[0] |= kc.as_modifier_bit(),
+            kc if bitmap_bit(kc as u8).is_none() || self.is_pressed(kc) => (),
             _ => {
                 // handle boot scancode array first
-                self.0[2..]
+                self.0[2..8]
                     .iter_mut()
                     .find(|c| **c == 0)
                     .map(|c| *c = kc as u8)
                     .unwrap_or_else(|| self.set_all(ErrorRollOver));
 
                 // handle the NKRO bitmap
-                let bits = &mut self.0[8..];
-                let kc_bit = kc as u8;
-                match kc_bit {
-                    0..=3 => (),
-                    4..=7 => {
-                        bits[0] |= 1 << kc_bit;
-                    }
-                    8..=15 => {
-                        bits[1] |= 1 << (kc_bit - 8);
-                    }
-                    16..=23 => {
-                        bits[2] |= 1 << (kc_bit - 16);
-                    }
-                    24..=31 => {
-                        bits[3] |= 1 << (kc_bit - 24);
-                    }
-                    32..=39 => {
-                        bits[4] |= 1 << (kc_bit - 32);
-                    }
-                    40..=47 => {
-                        bits[5] |= 1 << (kc_bit - 40);
-                    }
-                    48..=55 => {
-                        bits[6] |= 1 << (kc_bit - 48);
-                    }
-                    56..=63 => {
-                        bits[7] |= 1 << (kc_bit - 56);
-                    }
-                    64..=71 => {
-                        bits[8] |= 1 << (kc_bit - 64);
-                    }
-                    72..=79 => {
-                        bits[9] |= 1 << (kc_bit - 72);
-                    }
-                    80..=87 => {
-                        bits[10] |= 1 << (kc_bit - 80);
-                    }
-                    88..=95 => {
-                        bits[11] |= 1 << (kc_bit - 88);
-                    }
-                    96..=103 => {
-                        bits[12] |= 1 << (kc_bit - 96);
+                if let Some((byte, mask)) = bitmap_bit(kc as u8) {
+                    self.0[byte] |= mask;
+                }
+            }
+        }
+    }
+
+    /// Remove the given key code from the report. The key is cleared
+    /// from the modifier byte or the NKRO bitmap, and removed from the
+    /// BOOT array with the keys after it shifted down, so the array
+    /// stays packed from the front like a boot keyboard's would.
+    ///
+    /// If the BOOT array had rolled over, it is rebuilt from the bitmap
+    /// once the remaining keys fit in it again.
+    pub fn released(&mut self, kc: KeyCode) {
+        use KeyCode::*;
+        match kc {
+            No => (),
+            ErrorRollOver | PostFail | ErrorUndefined => self.sync_boot(),
+            kc if kc.is_modifier() => self.0[0] &= !kc.as_modifier_bit(),
+            _ => {
+                if let Some((byte, mask)) = bitmap_bit(kc as u8) {
+                    self.0[byte] &= !mask;
+                }
+
+                let boot = &mut self.0[2..8];
+                if let Some(i) = boot.iter().position(|c| *c == kc as u8) {
+                    boot.copy_within(i + 1.., i);
+                    boot[5] = 0;
+                } else if boot[0] == ErrorRollOver as u8 {
+                    self.sync_boot();
+                }
+            }
+        }
+    }
+
+    /// Release every key, leaving an empty report.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns `true` if the given key code is currently in the report.
+    pub fn is_pressed(&self, kc: KeyCode) -> bool {
+        match kc {
+            kc if kc.is_modifier() => self.0[0] & kc.as_modifier_bit() != 0,
+            kc => self.has_usage(kc as u8),
+        }
+    }
+
+    /// Returns `true` if the bit of the usage `code` is set in the NKRO
+    /// bitmap.
+    fn has_usage(&self, code: u8) -> bool {
+        matches!(bitmap_bit(code), Some((byte, mask)) if self.0[byte] & mask != 0)
+    }
+
+    /// Iterates over the usages set in the NKRO bitmap, including the
+    /// ones keyberon has no `KeyCode` for.
+    fn usages(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..BITMAP_KEYS).filter(move |code| self.has_usage(*code))
+    }
+
+    /// Iterates over the pressed keys: modifiers first, then the keys
+    /// of the NKRO bitmap in usage order.
+    pub fn pressed_keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.modifiers().chain(self.bitmap_keys())
+    }
+
+    /// Iterates over the pressed modifiers, from `LCtrl` to `RGui`.
+    pub fn modifiers(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        (0..8)
+            .filter(move |bit| self.0[0] & (1 << bit) != 0)
+            .filter_map(|bit| key_code(KeyCode::LCtrl as u8 + bit))
+    }
+
+    /// Iterates over the keys of the NKRO bitmap, in usage order.
+    pub fn bitmap_keys(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.usages().filter_map(key_code)
+    }
+
+    /// Returns the 6 entries of the BOOT array, as raw usages: packed
+    /// from the front and padded with 0, or all `ErrorRollOver`.
+    pub fn boot_array(&self) -> &[u8] {
+        &self.0[2..BOOT_REPORT_LEN]
+    }
+
+    /// Decodes a report as sent to the host: a whole `NKROReport`, or
+    /// the `BOOT_REPORT_LEN` bytes sent in BOOT protocol, in which case
+    /// the keys of the BOOT array are set in the bitmap as well. Returns
+    /// `None` for any other length.
+    ///
+    /// The report is taken as is, so that `mismatches` can tell whether
+    /// it was consistent.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut report = Self::default();
+        match bytes.len() {
+            NKRO_REPORT_LEN => report.0.copy_from_slice(bytes),
+            BOOT_REPORT_LEN => {
+                report.0[..BOOT_REPORT_LEN].copy_from_slice(bytes);
+                for &code in &bytes[2..] {
+                    if let Some((byte, mask)) = bitmap_bit(code) {
+                        report.0[byte] |= mask;
                     }
-                    _ => (),
                 }
             }
+            _ => return None,
         }
+        Some(report)
+    }
+
+    /// Iterates over the ways the first 8 bytes of the report disagree
+    /// with the bitmap, or with how `pressed` and `released` lay them
+    /// out. A report built by those has none, so these point at a bug,
+    /// or at a report that was not built by this firmware.
+    pub fn mismatches(&self) -> impl Iterator<Item = BootMismatch> + '_ {
+        use BootMismatch::*;
+        let boot = self.boot_array();
+        let rolled_over = boot.iter().all(|c| *c == KeyCode::ErrorRollOver as u8);
+
+        let reserved = Some(self.0[1]).filter(|b| *b != 0).map(Reserved);
+        let needless = rolled_over && self.usages().nth(boot.len()).is_none();
+        let gap = !rolled_over && boot.iter().skip_while(|c| **c != 0).any(|c| *c != 0);
+        let entries = boot
+            .iter()
+            .enumerate()
+            .filter(move |_| !rolled_over)
+            .filter_map(move |(i, &code)| match code {
+                0 => None,
+                code if boot[..i].contains(&code) => Some(Duplicate(code)),
+                code if !self.has_usage(code) => Some(NotInBitmap(code)),
+                _ => None,
+            });
+        let missing = self
+            .usages()
+            .filter(move |code| !rolled_over && !boot.contains(code))
+            .map(NotInBoot);
+
+        reserved
+            .into_iter()
+            .chain(needless.then_some(NeedlessRollOver))
+            .chain(gap.then_some(Gap))
+            .chain(entries)
+            .chain(missing)
     }
 
     fn set_all(&mut self, kc: KeyCode) {
@@ -154,4 +321,1358 @@ impl NKROReport {
             *c = kc as u8;
         }
     }
+
+    /// Refill the BOOT array from the NKRO bitmap, rolling over if
+    /// more keys are held than it can report.
+    fn sync_boot(&mut self) {
+        let mut boot = [0u8; 6];
+        for (c, kc) in boot.iter_mut().zip(self.bitmap_keys()) {
+            *c = kc as u8;
+        }
+        if self.bitmap_keys().nth(boot.len()).is_some() {
+            boot = [KeyCode::ErrorRollOver as u8; 6];
+        }
+        self.0[2..8].copy_from_slice(&boot);
+    }
+}
+
+/// Prints the pressed keys like `LShift+A+B`: the modifiers, then the
+/// keys of the bitmap, with the usages keyberon has no `KeyCode` for in
+/// hex. An empty report prints nothing.
+impl fmt::Display for NKROReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys = self.modifiers().map(|kc| kc as u8).chain(self.usages());
+        for (i, code) in keys.enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", Usage(code))?;
+        }
+        Ok(())
+    }
+}
+
+/// A disagreement between the BOOT part of an `NKROReport` and its
+/// bitmap, found by `NKROReport::mismatches`. Usages are raw, since they
+/// may have no `KeyCode`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BootMismatch {
+    /// The reserved byte is not 0.
+    Reserved(u8),
+    /// The BOOT array rolled over, though the keys of the bitmap fit in
+    /// it.
+    NeedlessRollOver,
+    /// A key of the BOOT array comes after an empty entry.
+    Gap,
+    /// A key is twice in the BOOT array.
+    Duplicate(u8),
+    /// A key of the BOOT array is not in the bitmap.
+    NotInBitmap(u8),
+    /// A key of the bitmap is not in the BOOT array, which did not roll
+    /// over.
+    NotInBoot(u8),
+}
+
+impl fmt::Display for BootMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootMismatch::Reserved(b) => write!(f, "reserved byte is {:#04X}", b),
+            BootMismatch::NeedlessRollOver => {
+                f.write_str("BOOT array rolled over with 6 keys or less")
+            }
+            BootMismatch::Gap => f.write_str("BOOT array has a gap"),
+            BootMismatch::Duplicate(code) => write!(f, "{} is twice in BOOT array", Usage(code)),
+            BootMismatch::NotInBitmap(code) => {
+                write!(f, "{} is in BOOT array but not in bitmap", Usage(code))
+            }
+            BootMismatch::NotInBoot(code) => {
+                write!(f, "{} is in bitmap but not in BOOT array", Usage(code))
+            }
+        }
+    }
+}
+
+/// Prints a keyboard page usage as its `KeyCode`, or in hex if keyberon
+/// has none.
+struct Usage(u8);
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match key_code(self.0) {
+            Some(kc) => write!(f, "{:?}", kc),
+            None => write!(f, "{:#04X}", self.0),
+        }
+    }
+}
+
+/// Length in bytes of a BOOT protocol keyboard report: the modifier
+/// byte, the reserved byte, and the 6-byte scancode array.
+pub const BOOT_REPORT_LEN: usize = 8;
+
+/// Report descriptor of a plain BOOT protocol keyboard, from appendix B.1
+/// of the HID 1.11 spec: the modifier byte, a reserved byte and a 6-byte
+/// array of keycodes, plus the same LED output report as
+/// `NKRO_REPORT_DESCRIPTOR`. It describes the first `BOOT_REPORT_LEN`
+/// bytes of an `NKROReport`, for boot interfaces that should never send
+/// anything else.
+pub const BOOT_REPORT_DESCRIPTOR: &[u8] = &BOOT_DESCRIPTOR.build::<{ BOOT_DESCRIPTOR.len() }>();
+
+const BOOT_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x06) // Keyboard
+    .collection(Collection::Application)
+    .report_size(1)
+    .report_count(8)
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0xE0)
+    .usage_maximum(0xE7)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .input(MainFlags::VARIABLE) // Modifier byte
+    .report_count(1)
+    .report_size(8)
+    .input(MainFlags::CONSTANT) // Reserved byte
+    .report_count(5)
+    .report_size(1)
+    .usage_page(UsagePage::Leds)
+    .usage_minimum(0x01) // Num Lock
+    .usage_maximum(0x05) // Kana
+    .output(MainFlags::VARIABLE) // LED report
+    .report_count(1)
+    .report_size(3)
+    .output(MainFlags::CONSTANT) // LED report padding
+    .report_count(6)
+    .report_size(8)
+    .logical_minimum(0)
+    .logical_maximum(223)
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0x00)
+    .usage_maximum(0xDF)
+    .input(MainFlags::empty()) // Key array
+    .end_collection();
+
+const _: () = {
+    let reports = descriptor::expect_valid(BOOT_REPORT_DESCRIPTOR);
+    assert!(reports.input_len(0) == BOOT_REPORT_LEN);
+    assert!(reports.output_len(0) == 1);
+};
+
+/// The two protocols a boot interface can speak, as selected by the
+/// host with SET_PROTOCOL (see section 7.2.6 of the HID 1.11 spec).
+/// The values match the `wValue` of the request.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Protocol {
+    /// The fixed 8-byte BOOT report, used by BIOS/UEFI.
+    Boot = 0,
+    /// The report described by `NKRO_REPORT_DESCRIPTOR`.
+    Report = 1,
+}
+
+/// The keyboard reports sent in the REPORT protocol, a setting of the
+/// user rather than of the host.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ReportMode {
+    /// Whole `NKROReport`s, described by `NKRO_REPORT_DESCRIPTOR`.
+    #[default]
+    Nkro = 0,
+    /// Only their 8-byte BOOT prefix, described by
+    /// `BOOT_REPORT_DESCRIPTOR`, for KVM switches and hosts that cannot
+    /// handle the NKRO report.
+    SixKro = 1,
+}
+
+impl ReportMode {
+    /// Returns the other mode.
+    pub fn toggled(self) -> Self {
+        match self {
+            ReportMode::Nkro => ReportMode::SixKro,
+            ReportMode::SixKro => ReportMode::Nkro,
+        }
+    }
+
+    /// Returns the mode stored as `value`, if any.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ReportMode::Nkro),
+            1 => Some(ReportMode::SixKro),
+            _ => None,
+        }
+    }
+
+    /// Returns the report descriptor of the reports sent in this mode.
+    pub fn report_descriptor(self) -> &'static [u8] {
+        match self {
+            ReportMode::Nkro => NKRO_REPORT_DESCRIPTOR,
+            ReportMode::SixKro => BOOT_REPORT_DESCRIPTOR,
+        }
+    }
+}
+
+/// Tracks the protocol selected for our boot keyboard interface, and
+/// encodes an `NKROReport` the way the host expects to receive it.
+///
+/// Devices must come out of a USB reset in the REPORT protocol, and
+/// only switch to BOOT when the host asks for it, so that is where this
+/// starts and where `reset` puts it back. The `ReportMode` is ours to
+/// pick, and is kept across resets.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProtocolState {
+    protocol: Protocol,
+    mode: ReportMode,
+}
+
+impl Default for ProtocolState {
+    fn default() -> Self {
+        Self {
+            protocol: Protocol::Report,
+            mode: ReportMode::default(),
+        }
+    }
+}
+
+impl ProtocolState {
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Handle a SET_PROTOCOL request with the given `wValue`, returning
+    /// the newly selected protocol. Values other than 0 (BOOT) and 1
+    /// (REPORT) are rejected with `None`, and should be answered with a
+    /// stall.
+    pub fn set_protocol(&mut self, value: u16) -> Option<Protocol> {
+        self.protocol = match value {
+            0 => Protocol::Boot,
+            1 => Protocol::Report,
+            _ => return None,
+        };
+        Some(self.protocol)
+    }
+
+    /// Returns the reports sent in the REPORT protocol.
+    pub fn mode(&self) -> ReportMode {
+        self.mode
+    }
+
+    /// Select the reports sent in the REPORT protocol. The host only
+    /// learns of a change once it reads the report descriptor again.
+    pub fn set_mode(&mut self, mode: ReportMode) {
+        self.mode = mode;
+    }
+
+    /// Go back to the REPORT protocol, as required after a USB reset.
+    pub fn reset(&mut self) {
+        self.protocol = Protocol::Report;
+    }
+
+    /// Returns the bytes of `report` to send for the current protocol
+    /// and mode: the whole report in REPORT protocol and NKRO mode, the
+    /// 8-byte BOOT prefix otherwise.
+    pub fn encode<'a>(&self, report: &'a NKROReport) -> &'a [u8] {
+        match (self.protocol, self.mode) {
+            (Protocol::Report, ReportMode::Nkro) => report.as_bytes(),
+            _ => &report.as_bytes()[..BOOT_REPORT_LEN],
+        }
+    }
+}
+
+bitflags! {
+    /// The keyboard LED states the host sends in its output report, one
+    /// bit per usage of the LED page from Num Lock (1) to Kana (5). These
+    /// reflect the host's actual lock state, so they are what features
+    /// like caps-word or lock indicators should follow.
+    #[derive(Default)]
+    pub struct HostLeds: u8 {
+        const NUM_LOCK = 1 << 0;
+        const CAPS_LOCK = 1 << 1;
+        const SCROLL_LOCK = 1 << 2;
+        const COMPOSE = 1 << 3;
+        const KANA = 1 << 4;
+    }
+}
+
+impl HostLeds {
+    /// Parses an LED output report, as received on the interrupt OUT
+    /// endpoint or with SET_REPORT. Bytes after the first are ignored,
+    /// since some hosts pad output reports to the endpoint size. Returns
+    /// `None` for an empty report.
+    pub fn from_report(report: &[u8]) -> Option<Self> {
+        match report {
+            [leds, ..] => Some(Self::from_bits_truncate(*leds)),
+            [] => None,
+        }
+    }
+}
+
+/// Report descriptor of our Consumer Control collection, for media,
+/// volume, brightness and application launch keys. It reports up to 4
+/// simultaneously pressed usages of the Consumer page, as an array of
+/// 16-bit usage IDs under Report ID 1.
+pub const CONSUMER_REPORT_DESCRIPTOR: &[u8] =
+    &CONSUMER_DESCRIPTOR.build::<{ CONSUMER_DESCRIPTOR.len() }>();
+
+const CONSUMER_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::Consumer)
+    .usage(0x01) // Consumer Control
+    .collection(Collection::Application)
+    .report_id(CONSUMER_REPORT_ID)
+    .logical_minimum(0)
+    .logical_maximum(1023)
+    .usage_minimum(0)
+    .usage_maximum(1023)
+    .report_size(16)
+    .report_count(4)
+    .input(MainFlags::empty())
+    .end_collection();
+
+/// Report ID of `ConsumerReport`.
+pub const CONSUMER_REPORT_ID: u8 = 1;
+
+/// The usages of the Consumer page we can send, with their usage IDs
+/// from the HID Usage Tables.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConsumerUsage {
+    BrightnessUp = 0x6F,
+    BrightnessDown = 0x70,
+    NextTrack = 0xB5,
+    PreviousTrack = 0xB6,
+    Stop = 0xB7,
+    Eject = 0xB8,
+    PlayPause = 0xCD,
+    Mute = 0xE2,
+    VolumeUp = 0xE9,
+    VolumeDown = 0xEA,
+    TextEditor = 0x185,
+    Calculator = 0x192,
+    Browser = 0x196,
+    ScreenSaver = 0x19E,
+    Search = 0x221,
+    Back = 0x224,
+    Forward = 0x225,
+    BrowserStop = 0x226,
+    Refresh = 0x227,
+    ScrollUp = 0x233,
+    ScrollDown = 0x234,
+}
+
+impl ConsumerUsage {
+    /// Maps keyberon's unofficial media key codes to their Consumer
+    /// page usage. `MediaSleep` is a System Control usage rather than a
+    /// Consumer one, so like every other key code it maps to `None`.
+    pub fn from_key_code(kc: KeyCode) -> Option<Self> {
+        use ConsumerUsage::*;
+        Some(match kc {
+            KeyCode::MediaPlayPause => PlayPause,
+            KeyCode::MediaStopCD => Stop,
+            KeyCode::MediaPreviousSong => PreviousTrack,
+            KeyCode::MediaNextSong => NextTrack,
+            KeyCode::MediaEjectCD => Eject,
+            KeyCode::MediaVolUp => VolumeUp,
+            KeyCode::MediaVolDown => VolumeDown,
+            KeyCode::MediaMute => Mute,
+            KeyCode::MediaWWW => Browser,
+            KeyCode::MediaBack => Back,
+            KeyCode::MediaForward => Forward,
+            KeyCode::MediaStop => BrowserStop,
+            KeyCode::MediaFind => Search,
+            KeyCode::MediaScrollUp => ScrollUp,
+            KeyCode::MediaScrollDown => ScrollDown,
+            KeyCode::MediaEdit => TextEditor,
+            KeyCode::MeidaCoffee => ScreenSaver,
+            KeyCode::MediaRefresh => Refresh,
+            KeyCode::MediaCalc => Calculator,
+            _ => return None,
+        })
+    }
+}
+
+/// Length in bytes of a `ConsumerReport`, including its report ID.
+pub const CONSUMER_REPORT_LEN: usize = 9;
+
+/// Struct representing a report of `CONSUMER_REPORT_DESCRIPTOR`: the
+/// report ID, followed by 4 little endian usage IDs. Pressed usages are
+/// kept packed at the front, and unused slots are 0.
+#[repr(C)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConsumerReport([u8; CONSUMER_REPORT_LEN]);
+
+impl Default for ConsumerReport {
+    fn default() -> Self {
+        let mut report = [0; CONSUMER_REPORT_LEN];
+        report[0] = CONSUMER_REPORT_ID;
+        Self(report)
+    }
+}
+
+impl core::iter::FromIterator<KeyCode> for ConsumerReport {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut res = Self::default();
+        for kc in iter {
+            res.pressed(kc);
+        }
+        res
+    }
+}
+
+impl ConsumerReport {
+    /// Returns the report as a byte slice, including its report ID.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Add the Consumer usage of the given key code to the report. Key
+    /// codes without one are ignored, so every key of the layout can be
+    /// fed to both this and an `NKROReport`.
+    pub fn pressed(&mut self, kc: KeyCode) {
+        if let Some(usage) = ConsumerUsage::from_key_code(kc) {
+            self.pressed_usage(usage);
+        }
+    }
+
+    /// Remove the Consumer usage of the given key code from the report.
+    pub fn released(&mut self, kc: KeyCode) {
+        if let Some(usage) = ConsumerUsage::from_key_code(kc) {
+            self.released_usage(usage);
+        }
+    }
+
+    /// Add the given usage to the report. If all 4 slots are taken, the
+    /// usage is dropped.
+    pub fn pressed_usage(&mut self, usage: ConsumerUsage) {
+        if self.is_pressed(usage) {
+            return;
+        }
+        if let Some(slot) = self.0[1..].chunks_exact_mut(2).find(|s| *s == [0, 0]) {
+            slot.copy_from_slice(&(usage as u16).to_le_bytes());
+        }
+    }
+
+    /// Remove the given usage from the report, shifting the usages after
+    /// it down to keep the array packed.
+    pub fn released_usage(&mut self, usage: ConsumerUsage) {
+        let usage = (usage as u16).to_le_bytes();
+        let slots = &mut self.0[1..];
+        if let Some(i) = slots.chunks_exact(2).position(|s| s == usage) {
+            slots.copy_within(2 * i + 2.., 2 * i);
+            let len = slots.len();
+            slots[len - 2..].fill(0);
+        }
+    }
+
+    /// Returns `true` if the given usage is currently in the report.
+    pub fn is_pressed(&self, usage: ConsumerUsage) -> bool {
+        let usage = (usage as u16).to_le_bytes();
+        self.0[1..].chunks_exact(2).any(|s| s == usage)
+    }
+
+    /// Release every usage, leaving an empty report.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Report descriptor fragment of our System Control collection, for the
+/// power, sleep and wake keys of the Generic Desktop page. It reports
+/// one bit per usage, from System Power Down to System Wake Up, under
+/// Report ID 2.
+pub const SYSTEM_CONTROL_REPORT_DESCRIPTOR: &[u8] =
+    &SYSTEM_CONTROL_DESCRIPTOR.build::<{ SYSTEM_CONTROL_DESCRIPTOR.len() }>();
+
+const SYSTEM_CONTROL_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x80) // System Control
+    .collection(Collection::Application)
+    .report_id(SYSTEM_CONTROL_REPORT_ID)
+    .usage_minimum(0x81) // System Power Down
+    .usage_maximum(0x83) // System Wake Up
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(3)
+    .input(MainFlags::VARIABLE)
+    .report_count(5)
+    .input(MainFlags::CONSTANT) // padding
+    .end_collection();
+
+/// Report ID of `SystemControlReport`.
+pub const SYSTEM_CONTROL_REPORT_ID: u8 = 2;
+
+/// Report descriptor of the interface carrying both `ConsumerReport`s
+/// and `SystemControlReport`, told apart by their report IDs.
+pub const CONSUMER_SYSTEM_REPORT_DESCRIPTOR: &[u8] =
+    &concat::<{ CONSUMER_REPORT_DESCRIPTOR.len() + SYSTEM_CONTROL_REPORT_DESCRIPTOR.len() }>(
+        CONSUMER_REPORT_DESCRIPTOR,
+        SYSTEM_CONTROL_REPORT_DESCRIPTOR,
+    );
+
+const _: () = {
+    let reports = descriptor::expect_valid(CONSUMER_SYSTEM_REPORT_DESCRIPTOR);
+    // both reports are sent with their Report ID first
+    assert!(reports.input_len(CONSUMER_REPORT_ID) + 1 == CONSUMER_REPORT_LEN);
+    assert!(reports.input_len(SYSTEM_CONTROL_REPORT_ID) + 1 == SYSTEM_CONTROL_REPORT_LEN);
+};
+
+/// Concatenates two report descriptors at compile time. `N` must be
+/// the sum of their lengths.
+const fn concat<const N: usize>(a: &[u8], b: &[u8]) -> [u8; N] {
+    assert!(a.len() + b.len() == N);
+    let mut res = [0; N];
+    let mut i = 0;
+    while i < a.len() {
+        res[i] = a[i];
+        i += 1;
+    }
+    while i < N {
+        res[i] = b[i - a.len()];
+        i += 1;
+    }
+    res
+}
+
+/// The System Control usages of the Generic Desktop page we can send.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SystemControl {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+impl SystemControl {
+    /// Maps keyberon's `MediaSleep` to System Sleep. keyberon has no key
+    /// codes for the other usages; the keyboard page `Power` key is sent
+    /// with the keyboard report instead.
+    pub fn from_key_code(kc: KeyCode) -> Option<Self> {
+        match kc {
+            KeyCode::MediaSleep => Some(SystemControl::Sleep),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << (self as u8 - SystemControl::PowerDown as u8)
+    }
+}
+
+/// Length in bytes of a `SystemControlReport`, including its report ID.
+pub const SYSTEM_CONTROL_REPORT_LEN: usize = 2;
+
+/// Struct representing a report of `SYSTEM_CONTROL_REPORT_DESCRIPTOR`:
+/// the report ID, followed by a bitfield of the `SystemControl` usages.
+#[repr(C)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SystemControlReport([u8; SYSTEM_CONTROL_REPORT_LEN]);
+
+impl Default for SystemControlReport {
+    fn default() -> Self {
+        Self([SYSTEM_CONTROL_REPORT_ID, 0])
+    }
+}
+
+impl core::iter::FromIterator<KeyCode> for SystemControlReport {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut res = Self::default();
+        for kc in iter {
+            res.pressed(kc);
+        }
+        res
+    }
+}
+
+impl SystemControlReport {
+    /// Returns the report as a byte slice, including its report ID.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Add the System Control usage of the given key code to the
+    /// report. Key codes without one are ignored.
+    pub fn pressed(&mut self, kc: KeyCode) {
+        if let Some(usage) = SystemControl::from_key_code(kc) {
+            self.pressed_usage(usage);
+        }
+    }
+
+    /// Remove the System Control usage of the given key code from the
+    /// report.
+    pub fn released(&mut self, kc: KeyCode) {
+        if let Some(usage) = SystemControl::from_key_code(kc) {
+            self.released_usage(usage);
+        }
+    }
+
+    /// Add the given usage to the report.
+    pub fn pressed_usage(&mut self, usage: SystemControl) {
+        self.0[1] |= usage.bit();
+    }
+
+    /// Remove the given usage from the report.
+    pub fn released_usage(&mut self, usage: SystemControl) {
+        self.0[1] &= !usage.bit();
+    }
+
+    /// Returns `true` if the given usage is currently in the report.
+    pub fn is_pressed(&self, usage: SystemControl) -> bool {
+        self.0[1] & usage.bit() != 0
+    }
+
+    /// Release every usage, leaving an empty report.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Report descriptor of our mouse interface. It has no Report ID, so the
+/// first 3 bytes of a `MouseReport` are laid out like a BOOT protocol
+/// mouse report: 5 buttons and padding, then relative X and Y movement.
+/// They are followed by the vertical wheel and the horizontal wheel (AC
+/// Pan, on the Consumer page).
+pub const MOUSE_REPORT_DESCRIPTOR: &[u8] = &MOUSE_DESCRIPTOR.build::<{ MOUSE_DESCRIPTOR.len() }>();
+
+const MOUSE_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x02) // Mouse
+    .collection(Collection::Application)
+    .usage(0x01) // Pointer
+    .collection(Collection::Physical)
+    .usage_page(UsagePage::Buttons)
+    .usage_minimum(1)
+    .usage_maximum(5)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(5)
+    .input(MainFlags::VARIABLE) // Buttons
+    .report_size(3)
+    .report_count(1)
+    .input(MainFlags::CONSTANT) // Button padding
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x30) // X
+    .usage(0x31) // Y
+    .usage(0x38) // Wheel
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(3)
+    .input(MainFlags::VARIABLE.union(MainFlags::RELATIVE))
+    .usage_page(UsagePage::Consumer)
+    .usage(0x238) // AC Pan
+    .report_count(1)
+    .input(MainFlags::VARIABLE.union(MainFlags::RELATIVE)) // Horizontal wheel
+    .end_collection()
+    .end_collection();
+
+/// Length in bytes of a `MouseReport`.
+pub const MOUSE_REPORT_LEN: usize = 5;
+
+const _: () =
+    assert!(descriptor::expect_valid(MOUSE_REPORT_DESCRIPTOR).input_len(0) == MOUSE_REPORT_LEN);
+
+/// The mouse buttons of `MOUSE_REPORT_DESCRIPTOR`, in the order of the
+/// Button page.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MouseButton {
+    Left = 0,
+    Right,
+    Middle,
+    Back,
+    Forward,
+}
+
+/// Struct representing a report of `MOUSE_REPORT_DESCRIPTOR`: a
+/// bitfield of buttons, X and Y movement, then the vertical and
+/// horizontal wheels. Movements are relative, so a report only moves
+/// the pointer once, no matter how long it stays the same.
+#[repr(C)]
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct MouseReport([u8; MOUSE_REPORT_LEN]);
+
+impl MouseReport {
+    /// Returns the report as a byte slice
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Press the given button.
+    pub fn pressed(&mut self, button: MouseButton) {
+        self.0[0] |= 1 << button as u8;
+    }
+
+    /// Release the given button.
+    pub fn released(&mut self, button: MouseButton) {
+        self.0[0] &= !(1 << button as u8);
+    }
+
+    /// Returns `true` if the given button is pressed.
+    pub fn is_pressed(&self, button: MouseButton) -> bool {
+        self.0[0] & (1 << button as u8) != 0
+    }
+
+    /// Returns the bitfield of pressed buttons.
+    pub fn buttons(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// Set the pointer movement, positive `x` to the right and positive
+    /// `y` down.
+    pub fn set_movement(&mut self, x: i8, y: i8) {
+        self.0[1] = x as u8;
+        self.0[2] = y as u8;
+    }
+
+    /// Set the wheel movement, positive `vertical` up and positive
+    /// `horizontal` to the right.
+    pub fn set_wheel(&mut self, vertical: i8, horizontal: i8) {
+        self.0[3] = vertical as u8;
+        self.0[4] = horizontal as u8;
+    }
+
+    /// Returns the `(x, y)` pointer movement.
+    pub fn movement(&self) -> (i8, i8) {
+        (self.0[1] as i8, self.0[2] as i8)
+    }
+
+    /// Returns the `(vertical, horizontal)` wheel movement.
+    pub fn wheel(&self) -> (i8, i8) {
+        (self.0[3] as i8, self.0[4] as i8)
+    }
+}
+
+/// Report descriptor of our vendor defined raw HID interface, on the
+/// same usage page (0xFF60) and usage (0x61) as QMK's, so existing host
+/// tools can find it. Both directions carry `RAW_REPORT_LEN` opaque bytes.
+pub const RAW_REPORT_DESCRIPTOR: &[u8] = &RAW_DESCRIPTOR.build::<{ RAW_DESCRIPTOR.len() }>();
+
+const RAW_DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+    .usage_page(UsagePage::Vendor(0xFF60))
+    .usage(0x61)
+    .collection(Collection::Application)
+    .usage(0x62) // Data In
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(RAW_REPORT_LEN as u8)
+    .input(MainFlags::VARIABLE)
+    .usage(0x63) // Data Out
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(RAW_REPORT_LEN as u8)
+    .output(MainFlags::VARIABLE)
+    .end_collection();
+
+/// Length in bytes of the raw HID input and output reports.
+pub const RAW_REPORT_LEN: usize = 32;
+
+const _: () = {
+    let reports = descriptor::expect_valid(RAW_REPORT_DESCRIPTOR);
+    assert!(reports.input_len(0) == RAW_REPORT_LEN);
+    assert!(reports.output_len(0) == RAW_REPORT_LEN);
+};
+
+/// Most bytes a `DescriptorBuilder` can hold.
+pub const MAX_DESCRIPTOR_LEN: usize = 256;
+
+/// The usage pages our descriptors use, from the HID Usage Tables.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UsagePage {
+    GenericDesktop,
+    Keyboard,
+    Leds,
+    Buttons,
+    Consumer,
+    /// A vendor defined page, 0xFF00 to 0xFFFF.
+    Vendor(u16),
+}
+
+impl UsagePage {
+    /// Returns the ID of the page.
+    pub const fn id(self) -> u16 {
+        match self {
+            UsagePage::GenericDesktop => 0x01,
+            UsagePage::Keyboard => 0x07,
+            UsagePage::Leds => 0x08,
+            UsagePage::Buttons => 0x09,
+            UsagePage::Consumer => 0x0C,
+            UsagePage::Vendor(id) => id,
+        }
+    }
+}
+
+/// The kinds of collections, with the data of their Collection item.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+    Report = 0x03,
+    NamedArray = 0x04,
+    UsageSwitch = 0x05,
+    UsageModifier = 0x06,
+}
+
+bitflags! {
+    /// The data of an Input, Output or Feature item. Each flag is the
+    /// set bit of a pair, so the empty set is Data, Array, Absolute, and
+    /// `VARIABLE` alone is the usual Data, Variable, Absolute field.
+    /// `VOLATILE` is reserved in Input items.
+    pub struct MainFlags: u8 {
+        const CONSTANT = 1 << 0;
+        const VARIABLE = 1 << 1;
+        const RELATIVE = 1 << 2;
+        const WRAP = 1 << 3;
+        const NON_LINEAR = 1 << 4;
+        const NO_PREFERRED = 1 << 5;
+        const NULL_STATE = 1 << 6;
+        const VOLATILE = 1 << 7;
+    }
+}
+
+/// Builds a report descriptor out of typed items, at compile time.
+///
+/// Each item is written with the shortest data that holds its value,
+/// signed for the logical range and unsigned otherwise, like the
+/// descriptors of the HID spec do. Collections are counted as they are
+/// opened and closed, so that an End Collection too many, or one too
+/// few when calling `build`, fails the build:
+///
+/// ```
+/// use keeb::hid::{Collection, DescriptorBuilder, MainFlags, UsagePage};
+///
+/// const DESCRIPTOR: DescriptorBuilder = DescriptorBuilder::new()
+///     .usage_page(UsagePage::Buttons)
+///     .collection(Collection::Application)
+///     .usage_minimum(1)
+///     .usage_maximum(8)
+///     .logical_minimum(0)
+///     .logical_maximum(1)
+///     .report_size(1)
+///     .report_count(8)
+///     .input(MainFlags::VARIABLE)
+///     .end_collection();
+/// const BYTES: &[u8] = &DESCRIPTOR.build::<{ DESCRIPTOR.len() }>();
+/// ```
+///
+/// It only checks the collections; see `descriptor::parse` for the
+/// rest.
+#[derive(Debug, Clone, Copy)]
+pub struct DescriptorBuilder {
+    bytes: [u8; MAX_DESCRIPTOR_LEN],
+    len: usize,
+    depth: usize,
+}
+
+impl Default for DescriptorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DescriptorBuilder {
+    // Item prefixes, without their size.
+    const INPUT: u8 = 0x80;
+    const OUTPUT: u8 = 0x90;
+    const COLLECTION: u8 = 0xA0;
+    const FEATURE: u8 = 0xB0;
+    const END_COLLECTION: u8 = 0xC0;
+    const USAGE_PAGE: u8 = 0x04;
+    const LOGICAL_MINIMUM: u8 = 0x14;
+    const LOGICAL_MAXIMUM: u8 = 0x24;
+    const REPORT_SIZE: u8 = 0x74;
+    const REPORT_ID: u8 = 0x84;
+    const REPORT_COUNT: u8 = 0x94;
+    const USAGE: u8 = 0x08;
+    const USAGE_MINIMUM: u8 = 0x18;
+    const USAGE_MAXIMUM: u8 = 0x28;
+
+    /// Returns an empty descriptor.
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; MAX_DESCRIPTOR_LEN],
+            len: 0,
+            depth: 0,
+        }
+    }
+
+    /// Returns the length of the descriptor so far.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no item was added yet.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the descriptor, which must be `N` bytes long. Panics if a
+    /// collection is left open.
+    pub const fn build<const N: usize>(&self) -> [u8; N] {
+        assert!(self.depth == 0, "Collection without End Collection");
+        assert!(self.len == N, "descriptor length differs from N");
+        let mut res = [0; N];
+        let mut i = 0;
+        while i < N {
+            res[i] = self.bytes[i];
+            i += 1;
+        }
+        res
+    }
+
+    /// Adds a short item with `size` bytes of `data`.
+    const fn item(mut self, prefix: u8, data: u32, size: usize) -> Self {
+        assert!(
+            self.len + 1 + size <= MAX_DESCRIPTOR_LEN,
+            "descriptor longer than MAX_DESCRIPTOR_LEN"
+        );
+        self.bytes[self.len] = prefix
+            | match size {
+                4 => 3,
+                size => size as u8,
+            };
+        let mut i = 0;
+        while i < size {
+            self.bytes[self.len + 1 + i] = (data >> (8 * i)) as u8;
+            i += 1;
+        }
+        self.len += 1 + size;
+        self
+    }
+
+    /// Adds an item with the shortest data that holds `data` unsigned.
+    const fn unsigned(self, prefix: u8, data: u32) -> Self {
+        let size = match data {
+            0..=0xFF => 1,
+            0x100..=0xFFFF => 2,
+            _ => 4,
+        };
+        self.item(prefix, data, size)
+    }
+
+    /// Adds an item with the shortest data that holds `data` signed.
+    const fn signed(self, prefix: u8, data: i32) -> Self {
+        let size = match data {
+            -0x80..=0x7F => 1,
+            -0x8000..=0x7FFF => 2,
+            _ => 4,
+        };
+        self.item(prefix, data as u32, size)
+    }
+
+    /// Selects the usage page of the next usages.
+    pub const fn usage_page(self, page: UsagePage) -> Self {
+        self.unsigned(Self::USAGE_PAGE, page.id() as u32)
+    }
+
+    /// Adds a usage of the current page to the next main item.
+    pub const fn usage(self, usage: u16) -> Self {
+        self.unsigned(Self::USAGE, usage as u32)
+    }
+
+    /// Starts a range of usages for the next main item.
+    pub const fn usage_minimum(self, usage: u16) -> Self {
+        self.unsigned(Self::USAGE_MINIMUM, usage as u32)
+    }
+
+    /// Ends the range of usages started by `usage_minimum`, inclusive.
+    pub const fn usage_maximum(self, usage: u16) -> Self {
+        self.unsigned(Self::USAGE_MAXIMUM, usage as u32)
+    }
+
+    /// Sets the smallest value of the fields of the next main items.
+    pub const fn logical_minimum(self, min: i32) -> Self {
+        self.signed(Self::LOGICAL_MINIMUM, min)
+    }
+
+    /// Sets the largest value of the fields of the next main items.
+    pub const fn logical_maximum(self, max: i32) -> Self {
+        self.signed(Self::LOGICAL_MAXIMUM, max)
+    }
+
+    /// Sets the size in bits of the fields of the next main items.
+    pub const fn report_size(self, bits: u8) -> Self {
+        self.unsigned(Self::REPORT_SIZE, bits as u32)
+    }
+
+    /// Sets the number of fields of the next main items.
+    pub const fn report_count(self, count: u8) -> Self {
+        self.unsigned(Self::REPORT_COUNT, count as u32)
+    }
+
+    /// Puts the next main items in the report with ID `id`, which must
+    /// not be 0.
+    pub const fn report_id(self, id: u8) -> Self {
+        assert!(id != 0, "Report ID 0 is reserved");
+        self.unsigned(Self::REPORT_ID, id as u32)
+    }
+
+    /// Opens a collection, to be closed by `end_collection`.
+    pub const fn collection(mut self, kind: Collection) -> Self {
+        self.depth += 1;
+        self.unsigned(Self::COLLECTION, kind as u32)
+    }
+
+    /// Closes the innermost collection. Panics if there is none.
+    pub const fn end_collection(mut self) -> Self {
+        assert!(self.depth > 0, "End Collection without Collection");
+        self.depth -= 1;
+        self.item(Self::END_COLLECTION, 0, 0)
+    }
+
+    /// Adds input fields, as described by the items before it.
+    pub const fn input(self, flags: MainFlags) -> Self {
+        self.unsigned(Self::INPUT, flags.bits() as u32)
+    }
+
+    /// Adds output fields, as described by the items before it.
+    pub const fn output(self, flags: MainFlags) -> Self {
+        self.unsigned(Self::OUTPUT, flags.bits() as u32)
+    }
+
+    /// Adds feature fields, as described by the items before it.
+    pub const fn feature(self, flags: MainFlags) -> Self {
+        self.unsigned(Self::FEATURE, flags.bits() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(keys: &[KeyCode]) -> NKROReport {
+        keys.iter().copied().collect()
+    }
+
+    #[test]
+    fn released_compacts_boot_array() {
+        use KeyCode::*;
+        let mut report = report(&[A, B, C, D]);
+        assert_eq!(
+            report.boot_array(),
+            [A as u8, B as u8, C as u8, D as u8, 0, 0]
+        );
+        report.released(B);
+        assert_eq!(report.boot_array(), [A as u8, C as u8, D as u8, 0, 0, 0]);
+        assert!(!report.is_pressed(B));
+        assert!(report.is_pressed(C));
+        report.released(A);
+        report.released(D);
+        assert_eq!(report.boot_array(), [C as u8, 0, 0, 0, 0, 0]);
+        assert_eq!(report.mismatches().count(), 0);
+    }
+
+    #[test]
+    fn seventh_key_rolls_over() {
+        use KeyCode::*;
+        let mut report = report(&[A, B, C, D, E, F]);
+        assert_eq!(report.boot_array(), [A, B, C, D, E, F].map(|kc| kc as u8));
+        report.pressed(G);
+        assert_eq!(report.boot_array(), [ErrorRollOver as u8; 6]);
+        // the bitmap still has every key
+        assert_eq!(report.bitmap_keys().count(), 7);
+        assert!(report.is_pressed(G));
+        assert_eq!(report.mismatches().count(), 0);
+    }
+
+    #[test]
+    fn release_after_roll_over_rebuilds_boot_array() {
+        use KeyCode::*;
+        let mut report = report(&[LShift, A, B, C, D, E, F, G]);
+        report.released(C);
+        assert_eq!(report.boot_array(), [A, B, D, E, F, G].map(|kc| kc as u8));
+        assert_eq!(report.as_bytes()[0], 0b10);
+        assert_eq!(report.mismatches().count(), 0);
+        // and it stays packed afterwards
+        report.released(A);
+        assert_eq!(
+            report.boot_array(),
+            [B as u8, D as u8, E as u8, F as u8, G as u8, 0]
+        );
+    }
+
+    #[test]
+    fn every_key_code_has_its_bit() {
+        for code in 0x04..=0xA4u8 {
+            let kc = key_code(code).unwrap();
+            let report = report(&[kc]);
+            let byte = 8 + code as usize / 8;
+            for (i, b) in report.as_bytes().iter().enumerate().skip(8) {
+                let expected = if i == byte { 1 << (code % 8) } else { 0 };
+                assert_eq!(*b, expected, "{:?} byte {}", kc, i);
+            }
+            assert!(report.is_pressed(kc));
+            assert_eq!(report.pressed_keys().collect::<heapless::Vec<_, 2>>(), [kc]);
+            assert_eq!(report.boot_array()[0], code);
+        }
+    }
+
+    #[test]
+    fn media_codes_are_ignored() {
+        for code in 0xE8..=0xFBu8 {
+            let kc = key_code(code).unwrap();
+            assert_eq!(report(&[kc]), NKROReport::default(), "{:?}", kc);
+        }
+    }
+
+    #[test]
+    fn host_leds_bits() {
+        for (bit, led) in [
+            HostLeds::NUM_LOCK,
+            HostLeds::CAPS_LOCK,
+            HostLeds::SCROLL_LOCK,
+            HostLeds::COMPOSE,
+            HostLeds::KANA,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(HostLeds::from_report(&[1 << bit]), Some(led));
+        }
+        // the padding bits are dropped
+        assert_eq!(HostLeds::from_report(&[0xE2]), Some(HostLeds::CAPS_LOCK));
+    }
+
+    #[test]
+    fn host_leds_report_lengths() {
+        assert_eq!(HostLeds::from_report(&[]), None);
+        assert_eq!(
+            HostLeds::from_report(&[0x03, 0xFF, 0xFF]),
+            Some(HostLeds::NUM_LOCK | HostLeds::CAPS_LOCK)
+        );
+    }
+
+    #[test]
+    fn consumer_usages_are_packed() {
+        let report: ConsumerReport = [KeyCode::MediaVolUp, KeyCode::A, KeyCode::MediaCalc]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            report.as_bytes(),
+            [CONSUMER_REPORT_ID, 0xE9, 0x00, 0x92, 0x01, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn consumer_release_compacts() {
+        let mut report: ConsumerReport = [
+            KeyCode::MediaMute,
+            KeyCode::MediaVolUp,
+            KeyCode::MediaPlayPause,
+        ]
+        .into_iter()
+        .collect();
+        report.released(KeyCode::MediaMute);
+        assert_eq!(
+            report.as_bytes(),
+            [CONSUMER_REPORT_ID, 0xE9, 0x00, 0xCD, 0x00, 0, 0, 0, 0]
+        );
+        report.released(KeyCode::MediaPlayPause);
+        report.released(KeyCode::MediaVolUp);
+        assert_eq!(report, ConsumerReport::default());
+    }
+
+    #[test]
+    fn fifth_consumer_usage_is_dropped() {
+        let mut report: ConsumerReport = [
+            KeyCode::MediaMute,
+            KeyCode::MediaVolUp,
+            KeyCode::MediaVolDown,
+            KeyCode::MediaPlayPause,
+            KeyCode::MediaCalc,
+        ]
+        .into_iter()
+        .collect();
+        assert!(!report.is_pressed(ConsumerUsage::Calculator));
+        assert!(report.is_pressed(ConsumerUsage::PlayPause));
+        // a freed slot takes the next press
+        report.released(KeyCode::MediaMute);
+        report.pressed(KeyCode::MediaCalc);
+        let id = CONSUMER_REPORT_ID;
+        assert_eq!(
+            report.as_bytes(),
+            [id, 0xE9, 0x00, 0xEA, 0x00, 0xCD, 0x00, 0x92, 0x01]
+        );
+    }
+
+    #[test]
+    fn system_control_bytes() {
+        let mut report = SystemControlReport::default();
+        assert_eq!(report.as_bytes(), [2, 0]);
+        report.pressed(KeyCode::MediaSleep);
+        assert_eq!(report.as_bytes(), [2, 0b010]);
+        assert!(report.is_pressed(SystemControl::Sleep));
+        // key codes without a System Control usage are ignored
+        report.pressed(KeyCode::A);
+        assert_eq!(report.as_bytes(), [2, 0b010]);
+        report.released(KeyCode::MediaSleep);
+        assert_eq!(report.as_bytes(), [2, 0]);
+    }
+
+    #[test]
+    fn nkro_descriptor_bytes() {
+        // the hand written descriptor the builder replaced
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x05, 0x01, 0x09, 0x06, 0xA1, 0x01,
+            0x75, 0x01, 0x95, 0x08, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7,
+            0x15, 0x00, 0x25, 0x01, 0x81, 0x02,
+            0x95, 0x38, 0x75, 0x01, 0x81, 0x01,
+            0x95, 0x05, 0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02,
+            0x95, 0x01, 0x75, 0x03, 0x91, 0x01,
+            0x95, 0xE0, 0x75, 0x01, 0x15, 0x00, 0x25, 0x01,
+            0x05, 0x07, 0x19, 0x00, 0x29, 0xDF, 0x81, 0x02,
+            0xC0,
+        ];
+        assert_eq!(NKRO_REPORT_DESCRIPTOR, expected);
+    }
+
+    #[test]
+    fn encode_length_follows_protocol_and_mode() {
+        let report = report(&[KeyCode::A]);
+        for (protocol, mode, len) in [
+            (1, ReportMode::Nkro, NKRO_REPORT_LEN),
+            (1, ReportMode::SixKro, BOOT_REPORT_LEN),
+            (0, ReportMode::Nkro, BOOT_REPORT_LEN),
+            (0, ReportMode::SixKro, BOOT_REPORT_LEN),
+        ] {
+            let mut state = ProtocolState::default();
+            state.set_mode(mode);
+            state.set_protocol(protocol).unwrap();
+            let bytes = state.encode(&report);
+            assert_eq!(bytes.len(), len, "protocol {} in {:?}", protocol, mode);
+            assert_eq!(bytes, &report.as_bytes()[..len]);
+        }
+    }
+
+    #[test]
+    fn reset_keeps_the_mode() {
+        let mut state = ProtocolState::default();
+        state.set_mode(ReportMode::SixKro);
+        state.set_protocol(0).unwrap();
+        state.reset();
+        assert_eq!(state.protocol(), Protocol::Report);
+        assert_eq!(state.mode(), ReportMode::SixKro);
+    }
+
+    #[test]
+    fn release_of_absent_key_does_nothing() {
+        use KeyCode::*;
+        let mut report = report(&[A, LCtrl]);
+        let before = report.clone();
+        report.released(B);
+        report.released(RCtrl);
+        assert_eq!(report, before);
+    }
+
+    #[test]
+    fn from_bytes_of_both_lengths() {
+        use KeyCode::*;
+        let full = report(&[LShift, A, F13, Lang1]);
+        assert_eq!(NKROReport::from_bytes(full.as_bytes()), Some(full));
+
+        let boot = [0x02, 0, A as u8, B as u8, 0, 0, 0, 0];
+        let decoded = NKROReport::from_bytes(&boot).unwrap();
+        assert_eq!(decoded, report(&[LShift, A, B]));
+        assert_eq!(decoded.mismatches().count(), 0);
+
+        let bytes = [0; NKRO_REPORT_LEN + 1];
+        for len in [
+            0,
+            1,
+            BOOT_REPORT_LEN - 1,
+            BOOT_REPORT_LEN + 1,
+            NKRO_REPORT_LEN - 1,
+        ] {
+            assert_eq!(NKROReport::from_bytes(&bytes[..len]), None, "{} bytes", len);
+        }
+        assert_eq!(NKROReport::from_bytes(&bytes), None);
+    }
+
+    /// Returns the report of `keys`, with its BOOT array replaced by
+    /// `boot`.
+    fn with_boot(keys: &[KeyCode], boot: [KeyCode; 6]) -> NKROReport {
+        let mut bytes = report(keys).as_bytes().to_vec();
+        bytes[2..BOOT_REPORT_LEN].copy_from_slice(&boot.map(|kc| kc as u8));
+        NKROReport::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn each_mismatch() {
+        use BootMismatch::*;
+        use KeyCode::*;
+        let mismatches = |report: NKROReport| report.mismatches().collect::<Vec<_>>();
+
+        let mut bytes = report(&[A]).as_bytes().to_vec();
+        bytes[1] = 0x42;
+        let reserved = NKROReport::from_bytes(&bytes).unwrap();
+        assert_eq!(mismatches(reserved), [Reserved(0x42)]);
+
+        let rolled_over = with_boot(&[A], [ErrorRollOver; 6]);
+        assert_eq!(mismatches(rolled_over), [NeedlessRollOver]);
+        let gap = with_boot(&[A, B], [A, No, B, No, No, No]);
+        assert_eq!(mismatches(gap), [Gap]);
+        let duplicate = with_boot(&[A], [A, A, No, No, No, No]);
+        assert_eq!(mismatches(duplicate), [Duplicate(A as u8)]);
+        let not_in_bitmap = with_boot(&[A], [A, B, No, No, No, No]);
+        assert_eq!(mismatches(not_in_bitmap), [NotInBitmap(B as u8)]);
+        let not_in_boot = with_boot(&[A, B], [A, No, No, No, No, No]);
+        assert_eq!(mismatches(not_in_boot), [NotInBoot(B as u8)]);
+
+        let all = with_boot(&[A, B, C], [C, No, C, D, No, No]);
+        assert_eq!(
+            mismatches(all),
+            [
+                Gap,
+                Duplicate(C as u8),
+                NotInBitmap(D as u8),
+                NotInBoot(A as u8),
+                NotInBoot(B as u8),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_display() {
+        use KeyCode::*;
+        assert_eq!(report(&[]).to_string(), "");
+        assert_eq!(report(&[A]).to_string(), "A");
+        assert_eq!(report(&[B, A, LShift]).to_string(), "LShift+A+B");
+        assert_eq!(report(&[RGui, LCtrl, F13]).to_string(), "LCtrl+RGui+F13");
+
+        // a usage keyberon has no key code for
+        let mut bytes = report(&[A]).as_bytes().to_vec();
+        let (byte, mask) = bitmap_bit(0xA5).unwrap();
+        bytes[byte] |= mask;
+        let report = NKROReport::from_bytes(&bytes).unwrap();
+        assert_eq!(report.to_string(), "A+0xA5");
+    }
+
+    #[test]
+    fn mismatch_display() {
+        use BootMismatch::*;
+        let messages = [
+            (Reserved(0x42), "reserved byte is 0x42"),
+            (
+                NeedlessRollOver,
+                "BOOT array rolled over with 6 keys or less",
+            ),
+            (Gap, "BOOT array has a gap"),
+            (Duplicate(0x04), "A is twice in BOOT array"),
+            (NotInBitmap(0x05), "B is in BOOT array but not in bitmap"),
+            (NotInBoot(0xA5), "0xA5 is in bitmap but not in BOOT array"),
+        ];
+        for (mismatch, message) in messages {
+            assert_eq!(mismatch.to_string(), message);
+        }
+    }
 }
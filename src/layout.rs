@@ -0,0 +1,288 @@
+//! The keymap pipeline, from debounced matrix states to reports.
+//!
+//! `Keyboard` turns the changes between two `MatrixState`s into key
+//! events for a keyberon `Layout`, which gives us layers, tap-hold and
+//! multiple key actions, and ticks it along a millisecond clock. The key
+//! codes of the layout are then collected into our reports.
+//!
+//! keyberon 0.1 has no custom actions, so a `Keyboard` takes a table of
+//! key codes standing for custom actions of any type `T` instead: such a
+//! key code is never sent to the host, and `custom_actions` lists the
+//! actions of the ones held. The firmware's are `Custom`, named in
+//! `keymap.toml`: build.rs gives each one a key code the keymap does not
+//! use otherwise, so that every key code the keymap binds still reaches
+//! the host, and generates the table.
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Event, Layers, Layout};
+
+use crate::hid::{ConsumerReport, NKROReport, SystemControlReport};
+use crate::matrix::MatrixState;
+use crate::mouse::MouseKey;
+
+/// The actions of the keymap that are not key codes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Custom {
+    /// A key of the mouse keys engine: `Mouse(Up)` or `MouseButton(Left)`
+    /// in the keymap, see `crate::mouse`.
+    Mouse(MouseKey),
+    /// Switch the keyboard reports between NKRO and 6KRO:
+    /// `ToggleReportMode` in the keymap.
+    ToggleReportMode,
+}
+
+/// Most ticks given to the layout in one `update`, bounding the time it
+/// takes when it was not called for a while. A keymap with longer
+/// tap-hold timeouts raises it to the longest one, so that a stall still
+/// lets a held tap-hold key turn into a hold.
+const MAX_TICKS: u32 = 100;
+
+/// The keymap pipeline of an `R`×`C` matrix, with custom actions of
+/// type `T`.
+pub struct Keyboard<T: 'static, const R: usize, const C: usize> {
+    layout: Layout,
+    custom: &'static [(KeyCode, T)],
+    keys: MatrixState<R, C>,
+    last: Option<u32>,
+    max_ticks: u32,
+}
+
+impl<T: 'static, const R: usize, const C: usize> Keyboard<T, R, C> {
+    /// Creates a pipeline for `layers`, indexed by row then column, and
+    /// the key codes that stand for custom actions, with no key held.
+    pub fn new(layers: Layers, custom: &'static [(KeyCode, T)]) -> Self {
+        Self {
+            layout: Layout::new(layers),
+            custom,
+            keys: MatrixState::new(),
+            last: None,
+            max_ticks: layers
+                .iter()
+                .flat_map(|layer| layer.iter())
+                .flat_map(|row| row.iter())
+                .map(longest_timeout)
+                .fold(MAX_TICKS, u32::max),
+        }
+    }
+
+    /// Feeds the debounced `keys` at time `now`, in ms. The layout gets
+    /// one tick per ms since the last update, then an event for every key
+    /// that changed. It handles at most one event per tick, so this should
+    /// be called at least once per ms. After a longer pause, the ticks are
+    /// capped at `MAX_TICKS` or the longest tap-hold timeout of the
+    /// layers, whichever is longer.
+    ///
+    /// The ticks come first because a tap only shows up in the key codes
+    /// until the layout handles the release that caused it, on the next
+    /// tick: this way, it stays there until the next update.
+    pub fn update(&mut self, keys: &MatrixState<R, C>, now: u32) {
+        // the work is done by the calls, the returned key codes are read
+        // later with `key_codes`
+        let ticks = self.last.map_or(0, |last| now.wrapping_sub(last));
+        for _ in 0..ticks.min(self.max_ticks) {
+            let _ = self.layout.tick();
+        }
+        self.last = Some(now);
+
+        for (row, col, pressed) in keys.changes(&self.keys) {
+            let (row, col) = (row as u8, col as u8);
+            let event = match pressed {
+                true => Event::Press(row, col),
+                false => Event::Release(row, col),
+            };
+            let _ = self.layout.event(event);
+        }
+        self.keys = *keys;
+    }
+
+    /// Returns the key codes currently held, without the ones standing
+    /// for custom actions.
+    pub fn key_codes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.layout
+            .keycodes()
+            .filter(move |kc| self.custom_action(*kc).is_none())
+    }
+
+    /// Returns the custom actions currently held.
+    pub fn custom_actions(&self) -> impl Iterator<Item = &T> + '_ {
+        self.layout
+            .keycodes()
+            .filter_map(move |kc| self.custom_action(kc))
+    }
+
+    fn custom_action(&self, kc: KeyCode) -> Option<&'static T> {
+        let custom: &'static [(KeyCode, T)] = self.custom;
+        custom
+            .iter()
+            .find(|(code, _)| *code == kc)
+            .map(|(_, action)| action)
+    }
+
+    /// Returns the keyboard report of the keys currently held.
+    pub fn report(&self) -> NKROReport {
+        self.key_codes().collect()
+    }
+
+    /// Returns the Consumer Control report of the keys currently held.
+    pub fn consumer_report(&self) -> ConsumerReport {
+        self.key_codes().collect()
+    }
+
+    /// Returns the System Control report of the keys currently held.
+    pub fn system_report(&self) -> SystemControlReport {
+        self.key_codes().collect()
+    }
+}
+
+/// Returns the longest tap-hold timeout of `action`, in ms.
+fn longest_timeout(action: &Action) -> u32 {
+    match action {
+        Action::HoldTap { timeout, hold, tap } => (*timeout as u32)
+            .max(longest_timeout(hold))
+            .max(longest_timeout(tap)),
+        Action::MultipleActions(actions) => actions.iter().map(longest_timeout).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::Action::KeyCode as K;
+
+    static LAYERS: Layers = &[&[&[K(KeyCode::F13), K(KeyCode::ExSel), K(KeyCode::A)]]];
+
+    static CUSTOM: &[(KeyCode, Custom)] = &[(KeyCode::ExSel, Custom::Mouse(MouseKey::Up))];
+
+    /// A with LCtrl on hold, and B.
+    static HOLD_TAP: Layers = &[&[&[
+        Action::HoldTap {
+            timeout: 200,
+            hold: &K(KeyCode::LCtrl),
+            tap: &K(KeyCode::A),
+        },
+        K(KeyCode::B),
+    ]]];
+
+    fn keys<const C: usize>(held: &[usize]) -> MatrixState<1, C> {
+        let mut keys = MatrixState::new();
+        for &col in held {
+            keys.set(0, col, true);
+        }
+        keys
+    }
+
+    #[test]
+    fn custom_key_codes_are_filtered() {
+        let mut keyboard: Keyboard<Custom, 1, 3> = Keyboard::new(LAYERS, CUSTOM);
+        // one event per tick
+        for now in 0..3 {
+            keyboard.update(&keys(&[1, 2]), now);
+        }
+        assert_eq!(keyboard.key_codes().collect::<Vec<_>>(), [KeyCode::A]);
+        assert_eq!(keyboard.report(), [KeyCode::A].into_iter().collect());
+        assert_eq!(
+            keyboard.custom_actions().collect::<Vec<_>>(),
+            [&Custom::Mouse(MouseKey::Up)]
+        );
+    }
+
+    #[test]
+    fn f13_still_reports_f13() {
+        let mut keyboard: Keyboard<Custom, 1, 3> = Keyboard::new(LAYERS, CUSTOM);
+        keyboard.update(&keys(&[0]), 0);
+        keyboard.update(&keys(&[0]), 1);
+        assert_eq!(keyboard.report(), [KeyCode::F13].into_iter().collect());
+        assert_eq!(keyboard.custom_actions().count(), 0);
+    }
+
+    #[test]
+    fn f24_still_reports_f24() {
+        static LAYERS: Layers = &[&[&[K(KeyCode::F24), K(KeyCode::ExSel)]]];
+        static CUSTOM: &[(KeyCode, Custom)] = &[(KeyCode::ExSel, Custom::ToggleReportMode)];
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(LAYERS, CUSTOM);
+        hold(&mut keyboard, &[0], 0, 1);
+        assert_eq!(keyboard.report(), [KeyCode::F24].into_iter().collect());
+        assert_eq!(keyboard.custom_actions().count(), 0);
+
+        hold(&mut keyboard, &[0, 1], 2, 4);
+        assert_eq!(keyboard.report(), [KeyCode::F24].into_iter().collect());
+        assert_eq!(
+            keyboard.custom_actions().collect::<Vec<_>>(),
+            [&Custom::ToggleReportMode]
+        );
+    }
+
+    /// Feeds `keys` to `keyboard` once per ms from `from` to `to`, both
+    /// included, and returns its key codes at `to`.
+    fn hold<const C: usize>(
+        keyboard: &mut Keyboard<Custom, 1, C>,
+        held: &[usize],
+        from: u32,
+        to: u32,
+    ) -> Vec<KeyCode> {
+        for now in from..=to {
+            keyboard.update(&keys(held), now);
+        }
+        keyboard.key_codes().collect()
+    }
+
+    #[test]
+    fn events_after_ticks() {
+        let mut keyboard: Keyboard<Custom, 1, 3> = Keyboard::new(LAYERS, CUSTOM);
+        // the layout handles the press on the tick of the next update
+        assert_eq!(hold(&mut keyboard, &[2], 0, 0), []);
+        assert_eq!(hold(&mut keyboard, &[2], 1, 1), [KeyCode::A]);
+        // and the release likewise
+        assert_eq!(hold(&mut keyboard, &[], 2, 2), [KeyCode::A]);
+        assert_eq!(hold(&mut keyboard, &[], 3, 3), []);
+    }
+
+    #[test]
+    fn tap_stays_until_next_update() {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(HOLD_TAP, CUSTOM);
+        assert_eq!(hold(&mut keyboard, &[0], 0, 50), []);
+        // the release turns the key into a tap at once, and the tick of
+        // the next update handles the release
+        assert_eq!(hold(&mut keyboard, &[], 51, 51), [KeyCode::A]);
+        assert_eq!(hold(&mut keyboard, &[], 52, 52), []);
+    }
+
+    #[test]
+    fn hold_after_timeout() {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(HOLD_TAP, CUSTOM);
+        assert_eq!(hold(&mut keyboard, &[0], 0, 150), []);
+        assert_eq!(hold(&mut keyboard, &[0], 151, 250), [KeyCode::LCtrl]);
+        assert_eq!(hold(&mut keyboard, &[], 251, 252), []);
+    }
+
+    #[test]
+    fn other_key_during_hold_waits_for_it() {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(HOLD_TAP, CUSTOM);
+        hold(&mut keyboard, &[0], 0, 10);
+        // B waits for the tap-hold key to be resolved
+        assert_eq!(hold(&mut keyboard, &[0, 1], 11, 20), []);
+        assert_eq!(
+            hold(&mut keyboard, &[0, 1], 21, 250),
+            [KeyCode::LCtrl, KeyCode::B]
+        );
+    }
+
+    #[test]
+    fn stall_still_ends_hold_timeout() {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(HOLD_TAP, CUSTOM);
+        assert_eq!(hold(&mut keyboard, &[0], 0, 1), []);
+        // no update for a second: more than MAX_TICKS, but the 200 ms
+        // timeout must still expire
+        keyboard.update(&keys(&[0]), 1001);
+        assert_eq!(keyboard.key_codes().collect::<Vec<_>>(), [KeyCode::LCtrl]);
+    }
+
+    #[test]
+    fn max_ticks_covers_the_longest_timeout() {
+        let keyboard: Keyboard<Custom, 1, 3> = Keyboard::new(LAYERS, CUSTOM);
+        assert_eq!(keyboard.max_ticks, MAX_TICKS);
+        let keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(HOLD_TAP, CUSTOM);
+        assert_eq!(keyboard.max_ticks, 200);
+    }
+}
@@ -1,2 +1,20 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+// the keymaps generated by build.rs for the tests name the crate `keeb`,
+// like the binaries do
+#[cfg(test)]
+extern crate self as keeb;
+
+pub mod debounce;
+pub mod descriptor;
+pub mod direct;
 pub mod hid;
+pub mod layout;
+pub mod matrix;
+pub mod mcp23017;
+pub mod mouse;
+pub mod queue;
+pub mod settings;
+pub mod shift_register;
+pub mod split;
+pub mod trace;
+pub mod usb;
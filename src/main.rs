@@ -1,14 +1,33 @@
-//! # Pico USB 'Twitchy' Mouse Example
+//! # Pico USB Keyboard
 //!
-//! Creates a USB HID Class Pointing device (i.e. a virtual mouse) on a Pico
-//! board, with the USB driver running in the main thread.
+//! Creates a composite USB HID device on a Pico board, with one interface
+//! for each kind of report enabled by the cargo features (see
+//! `keeb::usb::Composite`), and the USB driver running in the USB
+//! interrupt.
 //!
-//! It generates movement reports which will twitch the cursor up and down by a
-//! few pixels, several times a second.
+//! A single switch on GPIO 0, read with `keeb::direct::DirectPins`, sends
+//! a key, and the on-board LED mirrors the host's Caps Lock state.
+//!
+//! The `Mouse(..)` and `MouseButton(..)` keys of the keymap drive
+//! `keeb::mouse`'s engine, whose reports go to the mouse interface.
+//!
+//! `ToggleReportMode` in the keymap toggles the keyboard reports between NKRO and
+//! 6KRO (see `keeb::hid::ReportMode`), when the boot keyboard is
+//! enabled. The choice is saved in the last sector of the flash, and the
+//! device restarts when the host has to enumerate it again to see the
+//! change.
+//!
+//! With the `split` feature, both halves of a split keyboard run this
+//! firmware, each with its switch on GPIO 0, linked by UART0 on GPIO 16
+//! (TX) and 17 (RX), crossed. GPIO 1 is tied to ground on the right half
+//! and left open on the left one: the left half's switch is the first
+//! column of the keymap, `keymap-split.toml` with this feature, and the
+//! right half's the second. Whichever half is powered through its USB
+//! port is the primary and talks to the host (see `keeb::split`).
 //!
 //! See the `Cargo.toml` file for Copyright and license details.
 //!
-//! This is a port of
+//! This started as a port of
 //! https://github.com/atsamd-rs/atsamd/blob/master/boards/itsybitsy_m0/examples/twitching_usb_mouse.rs
 
 #![no_std]
@@ -35,34 +54,87 @@ use rp_pico::hal::pac;
 use rp_pico::hal;
 
 // used for GPIO traits
+#[cfg(feature = "split")]
 use embedded_hal::digital::v2::InputPin;
+use embedded_hal::digital::v2::OutputPin;
+
+// used to hand the host LED state from the USB interrupt to the main loop
+use core::sync::atomic::{AtomicU8, Ordering};
 
 // USB Device support
 use usb_device::{class_prelude::*, prelude::*};
 
-// USB Human Interface Device (HID) Class support
-use usbd_hid::descriptor::generator_prelude::*;
-use usbd_hid::hid_class::HIDClass;
+// used to save the settings
+use rp2040_flash::flash;
+
+// used to flag a roll over in the keyboard report
+use keyberon::key_code::KeyCode;
 
 // import our keeb module
+use keeb::debounce::Debouncer;
+use keeb::direct::{ActiveLevel, DirectPins};
 use keeb::hid;
-use keyberon::key_code::KeyCode;
+use keeb::layout::{Custom, Keyboard};
+#[cfg(feature = "split")]
+use keeb::matrix::Halves;
+use keeb::matrix::{GhostFilter, GhostPolicy, KeySource};
+use keeb::mouse::{MouseConfig, MouseKeys};
+use keeb::queue::ReportQueue;
+use keeb::settings::{Settings, SETTINGS_LEN};
+#[cfg(feature = "split")]
+use keeb::split::{RemoteHalf, Role, Sender, Side};
+use keeb::usb::Composite;
+
+/// The keymap, generated by build.rs from `keymap.toml`: the `ROWS` and
+/// `COLS` of the matrix, the `KeyDebouncer` algorithm and its
+/// `DEBOUNCE_MS` delay, its keyberon `LAYERS`, and the key codes of its
+/// `CUSTOM_ACTIONS`.
+mod keymap {
+    include!(concat!(env!("OUT_DIR"), "/keymap.rs"));
+}
+
+/// Columns of each half of a split keyboard.
+#[cfg(feature = "split")]
+const HALF_COLS: usize = keymap::COLS / 2;
+
+/// Baud rate of the link between the halves.
+#[cfg(feature = "split")]
+const SPLIT_BAUD_RATE: fugit::HertzU32 = fugit::HertzU32::Hz(115_200);
+
+/// What to do about keys that may be ghosts, on a matrix without diodes.
+/// `None` for a matrix with diodes, where three held corners of a
+/// rectangle are just three held keys. This board reads its switch
+/// through `DirectPins`, one pin per switch, so it cannot ghost either.
+const GHOST_POLICY: Option<GhostPolicy> = None;
+
+/// Offset in the flash of the sector holding the settings, the last one
+/// of the 2 MiB flash of the Pico, left out of `FLASH` by `memory.x`.
+const SETTINGS_OFFSET: u32 = 2048 * 1024 - 4096;
+
+/// Address of the flash in the XIP address space.
+const XIP_BASE: u32 = 0x1000_0000;
+
 /// The USB Device Driver (shared with the interrupt).
 static mut USB_DEVICE: Option<UsbDevice<hal::usb::UsbBus>> = None;
 
 /// The USB Bus Driver (shared with the interrupt).
 static mut USB_BUS: Option<UsbBusAllocator<hal::usb::UsbBus>> = None;
 
-/// The USB Human Interface Device Driver (shared with the interrupt).
-static mut USB_HID: Option<HIDClass<hal::usb::UsbBus>> = None;
+/// The USB HID interfaces (shared with the interrupt).
+static mut USB_HID: Option<Composite<hal::usb::UsbBus>> = None;
+
+/// The host's keyboard LED state, as `hid::HostLeds` bits (written by the
+/// interrupt).
+static HOST_LEDS: AtomicU8 = AtomicU8::new(0);
 
 /// Entry point to our bare-metal application.
 ///
 /// The `#[entry]` macro ensures the Cortex-M start-up code calls this function
 /// as soon as all global variables are initialised.
 ///
-/// The function configures the RP2040 peripherals, then submits cursor movement
-/// updates periodically.
+/// The function configures the RP2040 peripherals and the composite USB
+/// device, then scans the keys and sends the keyboard, Consumer, System
+/// Control and mouse reports of the keymap to their interfaces.
 #[entry]
 fn main() -> ! {
     // Grab our singleton objects
@@ -94,6 +166,59 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
+    // the key switch, wired to ground on GPIO 0
+    let mut keys = DirectPins::new([[pins.gpio0.into_pull_up_input()]], ActiveLevel::Low);
+
+    let core = pac::CorePeripherals::take().unwrap();
+    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    // a free running µs counter, giving the debouncer its ms clock
+    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS);
+
+    // The strap pin tells the sides apart, so that the keys end up in the
+    // same columns whichever half has the host
+    #[cfg(feature = "split")]
+    let side = {
+        let strap = pins.gpio1.into_pull_up_input();
+        delay.delay_us(10);
+        Side::from_strap(strap.is_low().unwrap())
+    };
+
+    // The secondary half only sends its debounced keys to the primary,
+    // and never gets to the USB setup. The primary reads them next to its
+    // own, its columns first
+    #[cfg(feature = "split")]
+    let mut keys = {
+        let uart_pins = (
+            pins.gpio16.into_mode::<hal::gpio::FunctionUart>(),
+            pins.gpio17.into_mode::<hal::gpio::FunctionUart>(),
+        );
+        let uart = hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+            .enable(
+                hal::uart::UartConfig::new(
+                    SPLIT_BAUD_RATE,
+                    hal::uart::DataBits::Eight,
+                    None,
+                    hal::uart::StopBits::One,
+                ),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap();
+        let vbus = pins.vbus_detect.into_floating_input().is_high().unwrap();
+        if Role::from_vbus(vbus) == Role::Secondary {
+            let mut debouncer =
+                keymap::KeyDebouncer::<{ keymap::ROWS }, HALF_COLS>::new(keymap::DEBOUNCE_MS);
+            let mut sender = Sender::new();
+            loop {
+                let now = (timer.get_counter().ticks() / 1000) as u32;
+                let raw = keys.scan(&mut delay).unwrap();
+                let keys = debouncer.update(&raw, now);
+                sender.update(&keys, now, |frame| uart.write_full_blocking(frame));
+            }
+        }
+        Halves::<_, _, HALF_COLS, HALF_COLS>::new(keys, RemoteHalf::new(uart))
+    };
+
     // Set up the USB driver
     let usb_bus = UsbBusAllocator::new(hal::usb::UsbBus::new(
         pac.USBCTRL_REGS,
@@ -110,10 +235,17 @@ fn main() -> ! {
     // Grab a reference to the USB Bus allocator. We are promising to the
     // compiler not to take mutable access to this global variable whilst this
     // reference exists!
-    let bus_ref = unsafe { USB_BUS.as_ref().unwrap() };
+    let bus_ref = unsafe { (*core::ptr::addr_of!(USB_BUS)).as_ref().unwrap() };
 
-    // Set up the USB HID Class Device driver, providing Mouse Reports
-    let usb_hid = HIDClass::new(bus_ref, hid::NKRO_REPORT_DESCRIPTOR, 60);
+    // Set up the USB HID interfaces: keyboards providing NKRO Reports, and
+    // media and power keys, which live on the Consumer and Generic Desktop
+    // pages and cannot be part of the keyboard report. The report mode
+    // must be set before the host reads the report descriptors
+    let mut settings = load_settings();
+    let mut usb_hid = Composite::new(bus_ref, 1);
+    // a saved mode the interfaces of this build lack is left alone
+    usb_hid.set_keyboard_mode(settings.report_mode).ok();
+    settings.report_mode = usb_hid.keyboard_mode();
     unsafe {
         // Note (safety): This is safe as interrupts haven't been started yet.
         USB_HID = Some(usb_hid);
@@ -122,7 +254,7 @@ fn main() -> ! {
     // Create a USB device with a fake VID and PID
     let usb_dev = UsbDeviceBuilder::new(bus_ref, UsbVidPid(0x16c0, 0x27da))
         .manufacturer("Fake company")
-        .product("Twitchy Mousey")
+        .product("keeb")
         .serial_number("TEST")
         .device_class(0)
         .build();
@@ -131,41 +263,203 @@ fn main() -> ! {
         USB_DEVICE = Some(usb_dev);
     }
 
-    // setup GPIO pins
-
-    // used to track state of key switches
-    let key = pins.gpio0.into_pull_up_input();
+    // the on-board LED mirrors the host's Caps Lock state
+    let mut caps_led = pins.led.into_push_pull_output();
     unsafe {
         // Enable the USB interrupt
         pac::NVIC::unmask(hal::pac::Interrupt::USBCTRL_IRQ);
     };
-    let core = pac::CorePeripherals::take().unwrap();
-    let _delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let mut debouncer =
+        keymap::KeyDebouncer::<{ keymap::ROWS }, { keymap::COLS }>::new(keymap::DEBOUNCE_MS);
+    let mut ghost_filter = GHOST_POLICY.map(GhostFilter::new);
+    let mut keyboard: Keyboard<Custom, { keymap::ROWS }, { keymap::COLS }> =
+        Keyboard::new(keymap::LAYERS, keymap::CUSTOM_ACTIONS);
+    // A toggle held through a restart has to be released before it
+    // toggles again
+    let mut toggle_held = true;
 
-    // Move the cursor up and down every 200ms
+    // The queues of report changes waiting for the host. A protocol or
+    // report mode switch changes the encoding, so the keyboard report is
+    // sent again when it happens.
+    let mut report_queue: ReportQueue<hid::NKROReport, 8> = ReportQueue::new();
+    let mut consumer_queue: ReportQueue<hid::ConsumerReport, 4> = ReportQueue::new();
+    let mut system_queue: ReportQueue<hid::SystemControlReport, 4> = ReportQueue::new();
+    let mut protocol = hid::Protocol::Report;
+    // Mouse reports move the pointer by steps, so they are not
+    // deduplicated like the others: one the endpoint could not take yet
+    // waits here, and the engine is only asked for the next one once it
+    // is sent
+    let mut mouse_keys = MouseKeys::new(MouseConfig::default());
+    let mut mouse_report = None;
     loop {
-        if key.is_low().unwrap() {
-            let mut rep_up = hid::NKROReport::default();
-            rep_up.pressed(KeyCode::Y);
-            //push_mouse_movement(rep_up).ok().unwrap_or(0);
+        let leds = hid::HostLeds::from_bits_truncate(HOST_LEDS.load(Ordering::Relaxed));
+        if leds.contains(hid::HostLeds::CAPS_LOCK) {
+            caps_led.set_high().unwrap();
         } else {
-            let _rep_down = hid::NKROReport::default();
-            //push_mouse_movement(rep_down).ok().unwrap_or(0);
+            caps_led.set_low().unwrap();
+        }
+
+        let now = (timer.get_counter().ticks() / 1000) as u32;
+        #[cfg(feature = "split")]
+        keys.right_mut().expire(now);
+        let raw = keys.scan(&mut delay).unwrap();
+        #[cfg(feature = "split")]
+        let raw = side.left_first(&raw);
+        let mut keys = debouncer.update(&raw, now);
+        if let Some(filter) = &mut ghost_filter {
+            keys = filter.update(&keys);
         }
+        keyboard.update(&keys, now);
+        if keyboard_protocol() != protocol {
+            protocol = keyboard_protocol();
+            report_queue.invalidate();
+        }
+
+        let held = keyboard
+            .custom_actions()
+            .any(|action| *action == Custom::ToggleReportMode);
+        if held && !toggle_held {
+            // Only a mode the keyboard accepted is saved, so that a
+            // refused one cannot come back on the next start
+            let mode = settings.report_mode.toggled();
+            if let Ok(enumerate) = set_keyboard_mode(mode) {
+                settings.report_mode = mode;
+                save_settings(&settings);
+                if enumerate {
+                    // The RP2040 USB driver cannot force the host to
+                    // enumerate the device again, but a restart does
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                report_queue.invalidate();
+            }
+        }
+        toggle_held = held;
+
+        for (_, action) in keymap::CUSTOM_ACTIONS {
+            let Custom::Mouse(key) = *action else {
+                continue;
+            };
+            let held = keyboard.custom_actions().any(|held| held == action);
+            match (held, mouse_keys.is_held(key)) {
+                (true, false) => mouse_keys.press(key, now),
+                (false, true) => mouse_keys.release(key),
+                _ => {}
+            }
+        }
+        if mouse_report.is_none() {
+            mouse_report = mouse_keys.tick(now);
+        }
+        if let Some(report) = &mouse_report {
+            if !matches!(
+                push_mouse_report(report),
+                Err(usb_device::UsbError::WouldBlock)
+            ) {
+                mouse_report = None;
+            }
+        }
+
+        // A full queue hands the report back, it is pushed again next time
+        let mut report = keyboard.report();
+        if ghost_filter.as_ref().is_some_and(|f| f.is_rolled_over()) {
+            report.pressed(KeyCode::ErrorRollOver);
+        }
+        report_queue.push(report).ok();
+        consumer_queue.push(keyboard.consumer_report()).ok();
+        system_queue.push(keyboard.system_report()).ok();
+        report_queue.flush(&mut push_report).ok();
+        consumer_queue
+            .flush(&mut |r: &hid::ConsumerReport| push_consumer_report(r.as_bytes()))
+            .ok();
+        system_queue
+            .flush(&mut |r: &hid::SystemControlReport| push_consumer_report(r.as_bytes()))
+            .ok();
     }
 }
 
-/// Submit a new mouse movement report to the USB stack.
+/// Returns the protocol the host has selected for the boot keyboard.
+fn keyboard_protocol() -> hid::Protocol {
+    critical_section::with(|_| unsafe {
+        (*core::ptr::addr_of!(USB_HID))
+            .as_ref()
+            .map(|usb_hid| usb_hid.keyboard_protocol())
+    })
+    .unwrap()
+}
+
+/// Select the keyboard reports, returning `true` if the host has to
+/// enumerate the device again to see the change, or an error if the
+/// keyboard has no such reports.
+fn set_keyboard_mode(mode: hid::ReportMode) -> usb_device::Result<bool> {
+    critical_section::with(|_| unsafe {
+        (*core::ptr::addr_of_mut!(USB_HID))
+            .as_mut()
+            .map(|usb_hid| usb_hid.set_keyboard_mode(mode))
+    })
+    .unwrap()
+}
+
+/// Reads the settings saved in flash, or the defaults if there are none.
+fn load_settings() -> Settings {
+    let address = (XIP_BASE + SETTINGS_OFFSET) as *const u8;
+    // Note (safety): the settings sector is mapped, and only written by
+    // `save_settings`, with nothing reading it meanwhile
+    let bytes = unsafe { core::slice::from_raw_parts(address, SETTINGS_LEN) };
+    Settings::from_bytes(bytes).unwrap_or_default()
+}
+
+/// Saves the settings to flash, erasing the sector and programming its
+/// first page.
+///
+/// Nothing may run from flash meanwhile, so this blocks the USB
+/// interrupt for the tens of ms the erase takes.
+fn save_settings(settings: &Settings) {
+    let mut page = [0xFF; 256];
+    page[..SETTINGS_LEN].copy_from_slice(&settings.to_bytes());
+    cortex_m::interrupt::free(|_| unsafe {
+        // Note (safety): interrupts are disabled, the second core is not
+        // running, and nothing uses DMA
+        flash::flash_range_erase(SETTINGS_OFFSET, 4096, true);
+        flash::flash_range_program(SETTINGS_OFFSET, &page, true);
+    });
+}
+
+/// Submit a new keyboard report to the USB stack.
+///
+/// We do this with interrupts disabled, to avoid a race hazard with the USB IRQ.
+fn push_report(report: &hid::NKROReport) -> Result<usize, usb_device::UsbError> {
+    critical_section::with(|_| unsafe {
+        // Now interrupts are disabled, grab the global variable and, if
+        // available, send it a HID report
+        (*core::ptr::addr_of_mut!(USB_HID))
+            .as_mut()
+            .map(|usb_hid| usb_hid.push_keyboard(report))
+    })
+    .unwrap()
+}
+
+/// Submit a new Consumer or System Control report to the USB stack.
 ///
 /// We do this with interrupts disabled, to avoid a race hazard with the USB IRQ.
-//fn push_mouse_movement(report: hid::NKROReport) -> Result<usize, usb_device::UsbError> {
-//    critical_section::with(|_| unsafe {
-//        // Now interrupts are disabled, grab the global variable and, if
-//        // available, send it a HID report
-//        USB_HID.as_mut().map(|hid| hid.push_input(&report))
-//    })
-//    .unwrap()
-//}
+fn push_consumer_report(report: &[u8]) -> Result<usize, usb_device::UsbError> {
+    critical_section::with(|_| unsafe {
+        (*core::ptr::addr_of_mut!(USB_HID))
+            .as_mut()
+            .map(|usb_hid| usb_hid.push_consumer(report))
+    })
+    .unwrap()
+}
+
+/// Submit a new mouse report to the USB stack.
+///
+/// We do this with interrupts disabled, to avoid a race hazard with the USB IRQ.
+fn push_mouse_report(report: &hid::MouseReport) -> Result<usize, usb_device::UsbError> {
+    critical_section::with(|_| unsafe {
+        (*core::ptr::addr_of_mut!(USB_HID))
+            .as_mut()
+            .map(|usb_hid| usb_hid.push_mouse(report))
+    })
+    .unwrap()
+}
 
 /// This function is called whenever the USB Hardware generates an Interrupt
 /// Request.
@@ -173,9 +467,12 @@ fn main() -> ! {
 #[interrupt]
 unsafe fn USBCTRL_IRQ() {
     // Handle USB request
-    let usb_dev = USB_DEVICE.as_mut().unwrap();
-    let usb_hid = USB_HID.as_mut().unwrap();
-    usb_dev.poll(&mut [usb_hid]);
+    let usb_dev = (*core::ptr::addr_of_mut!(USB_DEVICE)).as_mut().unwrap();
+    let usb_hid = (*core::ptr::addr_of_mut!(USB_HID)).as_mut().unwrap();
+    usb_hid.poll(usb_dev);
+
+    // Publish the LED state the host may have just sent us
+    HOST_LEDS.store(usb_hid.host_leds().bits(), Ordering::Relaxed);
 }
 
 // End of file
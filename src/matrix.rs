@@ -0,0 +1,560 @@
+//! Key matrix scanning.
+//!
+//! A matrix of `R` rows and `C` columns has a diode on every switch, so
+//! that several keys can be held without ghosting. The diode direction
+//! decides which side is strobed and which side is read:
+//!
+//! * COL2ROW: the diodes point from the columns to the rows. Rows are
+//!   outputs, strobed low one at a time, and columns are inputs with
+//!   pull-ups that read low when their key on the strobed row is down.
+//! * ROW2COL: the other way around, columns are strobed and rows read.
+//!
+//! Without the diodes, current flows back through held keys: when three
+//! corners of a rectangle are held, the fourth reads as held too, or the
+//! strobes fight each other through the switches and the reading is
+//! anyone's guess. `GhostFilter` keeps such keys out for prototypes
+//! wired that way.
+//!
+//! Pins are generic over the embedded-hal digital traits, so every input
+//! pin (and every output pin) must be the same type. With most HALs this
+//! means using their type erased pins.
+//!
+//! Keyboards wired some other way give their keys as a `MatrixState` all
+//! the same, through `KeySource`, so that the rest of the pipeline does
+//! not care how the keys are read.
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// The keys held on an `R`×`C` matrix, one bit per key, one `u32` per
+/// row. Columns are limited to 32.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MatrixState<const R: usize, const C: usize>([u32; R]);
+
+impl<const R: usize, const C: usize> Default for MatrixState<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> MatrixState<R, C> {
+    const FITS: () = assert!(C <= 32, "a matrix has at most 32 columns");
+
+    /// Creates a state with no key held.
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        Self([0; R])
+    }
+
+    /// Creates a state from the bits of each row, bit `c` being column `c`.
+    pub const fn from_rows(rows: [u32; R]) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        Self(rows)
+    }
+
+    /// Returns the bits of each row, bit `c` being column `c`.
+    pub fn rows(&self) -> &[u32; R] {
+        &self.0
+    }
+
+    /// Returns `true` if the key at `row`, `col` is held.
+    pub fn is_pressed(&self, row: usize, col: usize) -> bool {
+        self.0[row] & (1 << col) != 0
+    }
+
+    /// Marks the key at `row`, `col` as held or not.
+    pub fn set(&mut self, row: usize, col: usize, pressed: bool) {
+        match pressed {
+            true => self.0[row] |= 1 << col,
+            false => self.0[row] &= !(1 << col),
+        }
+    }
+
+    /// Returns `true` if no key is held.
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&row| row == 0)
+    }
+
+    /// Returns the `(row, col)` of every held key, row by row.
+    pub fn pressed(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..R).flat_map(move |row| {
+            (0..C)
+                .filter(move |&col| self.is_pressed(row, col))
+                .map(move |col| (row, col))
+        })
+    }
+
+    /// Returns the keys that are, or would be, ghosts on a matrix
+    /// without diodes: the corners of a rectangle whose three other
+    /// corners are held. Held ones may be real keys or ghosts, there is
+    /// no telling; the others are where a ghost would show up, so that
+    /// when the three keys of an L are held, only its missing corner is
+    /// returned.
+    pub fn ghosts(&self) -> Self {
+        let mut ghosts = Self::new();
+        for r1 in 0..R {
+            for r2 in r1 + 1..R {
+                let (a, b) = (self.0[r1], self.0[r2]);
+                let both = a & b;
+                // the columns with both rows held make a rectangle with
+                // every other column, or with each other if there are two
+                let corners = match both.count_ones() {
+                    0 => 0,
+                    1 => !both,
+                    _ => !0,
+                };
+                ghosts.0[r1] |= b & corners;
+                ghosts.0[r2] |= a & corners;
+            }
+        }
+        ghosts
+    }
+
+    /// Returns the `(row, col, pressed)` of every key that changed from
+    /// `previous` to this state, row by row.
+    pub fn changes<'a>(
+        &'a self,
+        previous: &'a Self,
+    ) -> impl Iterator<Item = (usize, usize, bool)> + 'a {
+        (0..R).flat_map(move |row| {
+            let changed = self.0[row] ^ previous.0[row];
+            (0..C)
+                .filter(move |&col| changed & (1 << col) != 0)
+                .map(move |col| (row, col, self.is_pressed(row, col)))
+        })
+    }
+}
+
+/// Anything reading the raw state of the keys of an `R`×`C` layout, like
+/// a `Matrix`.
+pub trait KeySource<const R: usize, const C: usize> {
+    type Error;
+
+    /// Reads which keys are held, with `delay` for sources that have to
+    /// wait for their lines to settle.
+    fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, Self::Error>;
+}
+
+/// Two key sources side by side, like the halves of a split matrix,
+/// read as one: the `LC` columns of `left`, then the `RC` columns of
+/// `right`, make the `C` columns of the layout.
+pub struct Halves<A, B, const LC: usize, const RC: usize> {
+    left: A,
+    right: B,
+}
+
+impl<A, B, const LC: usize, const RC: usize> Halves<A, B, LC, RC> {
+    pub fn new(left: A, right: B) -> Self {
+        Self { left, right }
+    }
+
+    pub fn left_mut(&mut self) -> &mut A {
+        &mut self.left
+    }
+
+    pub fn right_mut(&mut self) -> &mut B {
+        &mut self.right
+    }
+
+    /// Gives both sources back.
+    pub fn release(self) -> (A, B) {
+        (self.left, self.right)
+    }
+}
+
+/// An error of one of the sources of `Halves`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HalfError<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B, const R: usize, const C: usize, const LC: usize, const RC: usize> KeySource<R, C>
+    for Halves<A, B, LC, RC>
+where
+    A: KeySource<R, LC>,
+    B: KeySource<R, RC>,
+{
+    type Error = HalfError<A::Error, B::Error>;
+
+    fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, Self::Error> {
+        const { assert!(LC + RC == C, "the halves must make up the columns") };
+        let left = self.left.scan(delay).map_err(HalfError::Left)?;
+        let right = self.right.scan(delay).map_err(HalfError::Right)?;
+        let mut rows = *left.rows();
+        for (row, right) in rows.iter_mut().zip(right.rows()) {
+            *row |= right.checked_shl(LC as u32).unwrap_or(0);
+        }
+        Ok(MatrixState::from_rows(rows))
+    }
+}
+
+/// What a `GhostFilter` does about keys that may be ghosts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GhostPolicy {
+    /// Hold back their presses, and report the other keys as usual.
+    Suppress,
+    /// Hold back their presses, and have the keyboard report roll over
+    /// while they are held, like it does when too many keys are held for
+    /// the BOOT protocol (see `GhostFilter::is_rolled_over`).
+    RollOver,
+}
+
+/// Keeps the keys that may be ghosts (see `MatrixState::ghosts`) out of
+/// the states of a matrix without diodes.
+///
+/// Keys already held when a rectangle appears stay held, since they were
+/// unambiguous when pressed, but the new presses in it are held back
+/// until the rectangle is gone. It should see debounced states, so that
+/// a bouncing key does not briefly make a rectangle.
+#[derive(Debug, Clone)]
+pub struct GhostFilter<const R: usize, const C: usize> {
+    policy: GhostPolicy,
+    keys: MatrixState<R, C>,
+    held_back: bool,
+}
+
+impl<const R: usize, const C: usize> GhostFilter<R, C> {
+    /// Creates a filter with no key held.
+    pub fn new(policy: GhostPolicy) -> Self {
+        Self {
+            policy,
+            keys: MatrixState::new(),
+            held_back: false,
+        }
+    }
+
+    /// Returns `keys` without the presses that may be ghosts.
+    pub fn update(&mut self, keys: &MatrixState<R, C>) -> MatrixState<R, C> {
+        let ghosts = keys.ghosts();
+        let mut filtered = MatrixState::new();
+        let mut held_back = false;
+        for row in 0..R {
+            let new = keys.0[row] & ghosts.0[row] & !self.keys.0[row];
+            filtered.0[row] = keys.0[row] & !new;
+            held_back |= new != 0;
+        }
+        self.keys = filtered;
+        self.held_back = held_back;
+        filtered
+    }
+
+    /// Returns `true` if the last `update` held back a press, and the
+    /// policy is `GhostPolicy::RollOver`: the keyboard report should then
+    /// be sent with `KeyCode::ErrorRollOver` pressed.
+    pub fn is_rolled_over(&self) -> bool {
+        self.held_back && self.policy == GhostPolicy::RollOver
+    }
+}
+
+/// Which side of the matrix is strobed, see the module documentation.
+enum Pins<I, O, const R: usize, const C: usize> {
+    Col2Row { rows: [O; R], cols: [I; C] },
+    Row2Col { rows: [I; R], cols: [O; C] },
+}
+
+/// A scanner for an `R`×`C` key matrix.
+pub struct Matrix<I, O, const R: usize, const C: usize> {
+    pins: Pins<I, O, R, C>,
+    settle_us: u32,
+}
+
+impl<I, O, E, const R: usize, const C: usize> Matrix<I, O, R, C>
+where
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+{
+    /// Settle delay used unless set with `with_settle_delay`.
+    pub const DEFAULT_SETTLE_US: u32 = 10;
+
+    /// Creates a scanner for a COL2ROW matrix, strobing the `rows`
+    /// outputs and reading the pulled-up `cols` inputs.
+    pub fn col2row(rows: [O; R], cols: [I; C]) -> Result<Self, E> {
+        Self::new(Pins::Col2Row { rows, cols })
+    }
+
+    /// Creates a scanner for a ROW2COL matrix, strobing the `cols`
+    /// outputs and reading the pulled-up `rows` inputs.
+    pub fn row2col(rows: [I; R], cols: [O; C]) -> Result<Self, E> {
+        Self::new(Pins::Row2Col { rows, cols })
+    }
+
+    fn new(pins: Pins<I, O, R, C>) -> Result<Self, E> {
+        let mut matrix = Self {
+            pins,
+            settle_us: Self::DEFAULT_SETTLE_US,
+        };
+        matrix.release_all()?;
+        Ok(matrix)
+    }
+
+    /// Sets the time to wait, in µs, between strobing a line and reading
+    /// the inputs, long enough for the inputs to settle through the
+    /// pull-ups and the wiring.
+    pub fn with_settle_delay(mut self, settle_us: u32) -> Self {
+        self.settle_us = settle_us;
+        self
+    }
+
+    /// Drives every strobe line high, which is their idle level.
+    fn release_all(&mut self) -> Result<(), E> {
+        match &mut self.pins {
+            Pins::Col2Row { rows, .. } => rows.iter_mut().try_for_each(|pin| pin.set_high()),
+            Pins::Row2Col { cols, .. } => cols.iter_mut().try_for_each(|pin| pin.set_high()),
+        }
+    }
+
+    /// Scans the whole matrix, strobing one line at a time, and returns
+    /// which keys are held.
+    pub fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, E> {
+        let mut state = MatrixState::new();
+        match &mut self.pins {
+            Pins::Col2Row { rows, cols } => {
+                for (row, strobe) in rows.iter_mut().enumerate() {
+                    strobe.set_low()?;
+                    delay.delay_us(self.settle_us);
+                    for (col, input) in cols.iter().enumerate() {
+                        state.set(row, col, input.is_low()?);
+                    }
+                    strobe.set_high()?;
+                }
+            }
+            Pins::Row2Col { rows, cols } => {
+                for (col, strobe) in cols.iter_mut().enumerate() {
+                    strobe.set_low()?;
+                    delay.delay_us(self.settle_us);
+                    for (row, input) in rows.iter().enumerate() {
+                        state.set(row, col, input.is_low()?);
+                    }
+                    strobe.set_high()?;
+                }
+            }
+        }
+        Ok(state)
+    }
+}
+
+impl<I, O, E, const R: usize, const C: usize> KeySource<R, C> for Matrix<I, O, R, C>
+where
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+{
+    type Error = E;
+
+    fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, E> {
+        Matrix::scan(self, delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    enum Step {
+        Low(usize),
+        High(usize),
+        Read(usize),
+        Delay(u32),
+    }
+
+    /// Output lines, input lines, and the held keys connecting them.
+    #[derive(Default)]
+    struct Wiring {
+        /// `(output, input)` of every held key.
+        keys: Vec<(usize, usize)>,
+        low: Vec<usize>,
+        log: Vec<Step>,
+    }
+
+    type Shared = Rc<RefCell<Wiring>>;
+
+    struct Output(usize, Shared);
+
+    impl OutputPin for Output {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut wiring = self.1.borrow_mut();
+            wiring.low.push(self.0);
+            wiring.log.push(Step::Low(self.0));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut wiring = self.1.borrow_mut();
+            wiring.low.retain(|line| *line != self.0);
+            wiring.log.push(Step::High(self.0));
+            Ok(())
+        }
+    }
+
+    struct Input(usize, Shared);
+
+    impl InputPin for Input {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            self.is_low().map(|low| !low)
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            let mut wiring = self.1.borrow_mut();
+            wiring.log.push(Step::Read(self.0));
+            let wiring = &*wiring;
+            Ok(wiring
+                .keys
+                .iter()
+                .any(|(out, input)| *input == self.0 && wiring.low.contains(out)))
+        }
+    }
+
+    struct Delay(Shared);
+
+    impl DelayUs<u32> for Delay {
+        fn delay_us(&mut self, us: u32) {
+            self.0.borrow_mut().log.push(Step::Delay(us));
+        }
+    }
+
+    fn outputs<const N: usize>(wiring: &Shared) -> [Output; N] {
+        core::array::from_fn(|line| Output(line, wiring.clone()))
+    }
+
+    fn inputs<const N: usize>(wiring: &Shared) -> [Input; N] {
+        core::array::from_fn(|line| Input(line, wiring.clone()))
+    }
+
+    /// The steps of strobing each of `strobes` lines and reading `reads`
+    /// lines, after `settle_us`.
+    fn strobes(strobes: usize, reads: usize, settle_us: u32) -> Vec<Step> {
+        (0..strobes)
+            .flat_map(|line| {
+                [Step::Low(line), Step::Delay(settle_us)]
+                    .into_iter()
+                    .chain((0..reads).map(Step::Read))
+                    .chain([Step::High(line)])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn col2row_strobes_rows() {
+        let wiring = Shared::default();
+        let mut matrix = Matrix::<_, _, 2, 3>::col2row(outputs(&wiring), inputs(&wiring))
+            .unwrap()
+            .with_settle_delay(25);
+        // idle high from the start
+        assert_eq!(wiring.borrow().log, [Step::High(0), Step::High(1)]);
+        wiring.borrow_mut().log.clear();
+        wiring.borrow_mut().keys = vec![(0, 2), (1, 0), (1, 1)];
+
+        let state = matrix.scan(&mut Delay(wiring.clone())).unwrap();
+        assert_eq!(state, MatrixState::from_rows([0b100, 0b011]));
+        assert_eq!(wiring.borrow().log, strobes(2, 3, 25));
+        assert!(wiring.borrow().low.is_empty());
+    }
+
+    #[test]
+    fn row2col_strobes_columns() {
+        let wiring = Shared::default();
+        let mut matrix = Matrix::<_, _, 2, 3>::row2col(inputs(&wiring), outputs(&wiring)).unwrap();
+        assert_eq!(
+            wiring.borrow().log,
+            [Step::High(0), Step::High(1), Step::High(2)]
+        );
+        wiring.borrow_mut().log.clear();
+        // the strobed side is the columns: (col, row)
+        wiring.borrow_mut().keys = vec![(2, 0), (0, 1), (1, 1)];
+
+        let state = matrix.scan(&mut Delay(wiring.clone())).unwrap();
+        assert_eq!(state, MatrixState::from_rows([0b100, 0b011]));
+        let settle = Matrix::<Input, Output, 2, 3>::DEFAULT_SETTLE_US;
+        assert_eq!(wiring.borrow().log, strobes(3, 2, settle));
+        assert!(wiring.borrow().low.is_empty());
+    }
+
+    fn rows(rows: [u32; 3]) -> MatrixState<3, 3> {
+        MatrixState::from_rows(rows)
+    }
+
+    #[test]
+    fn rectangle_is_all_ghosts() {
+        let rectangle = rows([0b011, 0, 0b011]);
+        assert_eq!(rectangle.ghosts(), rectangle);
+        // with a fifth key on the first row, the third corner of the
+        // second row would be a ghost, but not that fifth key
+        let wide = rows([0b111, 0, 0b011]);
+        assert_eq!(wide.ghosts(), rows([0b011, 0, 0b111]));
+    }
+
+    #[test]
+    fn l_shape_has_one_ghost_corner() {
+        assert_eq!(rows([0b001, 0b011, 0]).ghosts(), rows([0b010, 0, 0]));
+        assert_eq!(rows([0b111, 0b001, 0]).ghosts(), rows([0, 0b110, 0]));
+    }
+
+    #[test]
+    fn no_ghosts_without_rectangle() {
+        // two keys in one column, or in one row
+        assert!(rows([0b010, 0b010, 0]).ghosts().is_empty());
+        assert!(rows([0b011, 0, 0]).ghosts().is_empty());
+        // a diagonal
+        assert!(rows([0b001, 0b010, 0b100]).ghosts().is_empty());
+    }
+
+    /// Feeds `GhostFilter` with a rectangle made by pressing three keys
+    /// in turn, the fourth corner showing up with the third, and returns
+    /// what it let through and whether it rolled over at each step.
+    fn rectangle_through(policy: GhostPolicy) -> Vec<(MatrixState<3, 3>, bool)> {
+        let mut filter = GhostFilter::new(policy);
+        [
+            rows([0b001, 0, 0]),
+            rows([0b001, 0b001, 0]),
+            rows([0b011, 0b011, 0]),
+            rows([0b001, 0b001, 0]),
+        ]
+        .iter()
+        .map(|keys| (filter.update(keys), filter.is_rolled_over()))
+        .collect()
+    }
+
+    #[test]
+    fn suppress_holds_back_new_corners() {
+        assert_eq!(
+            rectangle_through(GhostPolicy::Suppress),
+            [
+                (rows([0b001, 0, 0]), false),
+                (rows([0b001, 0b001, 0]), false),
+                (rows([0b001, 0b001, 0]), false),
+                (rows([0b001, 0b001, 0]), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn roll_over_while_corners_are_held_back() {
+        assert_eq!(
+            rectangle_through(GhostPolicy::RollOver),
+            [
+                (rows([0b001, 0, 0]), false),
+                (rows([0b001, 0b001, 0]), false),
+                (rows([0b001, 0b001, 0]), true),
+                (rows([0b001, 0b001, 0]), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_lets_an_l_through() {
+        // with diodes on some keys, an L is three real keys
+        let mut filter = GhostFilter::new(GhostPolicy::RollOver);
+        let l = rows([0b001, 0b011, 0]);
+        assert_eq!(filter.update(&l), l);
+        assert!(!filter.is_rolled_over());
+    }
+}
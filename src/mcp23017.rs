@@ -0,0 +1,238 @@
+//! Key matrix scanning through an MCP23017 I/O expander.
+//!
+//! Split and handwired boards often put half of their matrix behind an
+//! MCP23017 on I2C, which has two 8-bit ports, A and B. `Mcp23017Matrix`
+//! uses port A for the columns, strobed low one at a time, and port B for
+//! the rows, read with the expander's own pull-ups: a ROW2COL matrix, with
+//! the diodes pointing from the rows to the columns. Its keys can then be
+//! put next to the ones read by the microcontroller with
+//! `matrix::Halves`.
+//!
+//! Every register access is a whole I2C transaction, which takes longer
+//! than any line needs to settle, so there is no settle delay.
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::matrix::{KeySource, MatrixState};
+
+/// The address of an MCP23017 with its A0 to A2 pins low. The other
+/// addresses go up to 0x27.
+pub const DEFAULT_ADDRESS: u8 = 0x20;
+
+/// The registers we use, at their addresses for the default IOCON.BANK
+/// of 0, where the registers of both ports alternate.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Register {
+    /// Direction of the pins of each port, 1 for input.
+    IodirA = 0x00,
+    IodirB = 0x01,
+    /// Pull-ups of the input pins of each port, 1 to enable.
+    GppuA = 0x0C,
+    GppuB = 0x0D,
+    /// Levels of the pins of each port.
+    GpioA = 0x12,
+    GpioB = 0x13,
+    /// Output latches of each port.
+    OlatA = 0x14,
+    OlatB = 0x15,
+}
+
+/// An MCP23017 on an I2C bus.
+pub struct Mcp23017<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Mcp23017<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    /// Creates a driver for the expander at `address` on `i2c`.
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    /// Gives the bus back.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Writes `value` to `register`.
+    pub fn write_register(&mut self, register: Register, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[register as u8, value])
+    }
+
+    /// Reads the value of `register`.
+    pub fn read_register(&mut self, register: Register) -> Result<u8, E> {
+        let mut value = [0];
+        self.i2c
+            .write_read(self.address, &[register as u8], &mut value)?;
+        Ok(value[0])
+    }
+}
+
+/// A scanner for an `R`×`C` key matrix on an MCP23017, with its columns
+/// on GPA0 to GPA(C-1) and its rows on GPB0 to GPB(R-1).
+pub struct Mcp23017Matrix<I2C, const R: usize, const C: usize> {
+    expander: Mcp23017<I2C>,
+}
+
+impl<I2C, E, const R: usize, const C: usize> Mcp23017Matrix<I2C, R, C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    const FITS: () = assert!(R <= 8 && C <= 8, "an MCP23017 port has 8 pins");
+
+    /// Bits of the port A pins used as columns.
+    const COLS: u8 = (((1u16 << C) - 1) & 0xFF) as u8;
+
+    /// Configures the expander: the column pins as outputs, driven high
+    /// until strobed, and every other pin as an input with its pull-up.
+    pub fn new(mut expander: Mcp23017<I2C>) -> Result<Self, E> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        // set the latches first, so that the columns start high
+        expander.write_register(Register::OlatA, 0xFF)?;
+        expander.write_register(Register::GppuA, !Self::COLS)?;
+        expander.write_register(Register::IodirA, !Self::COLS)?;
+        expander.write_register(Register::GppuB, 0xFF)?;
+        expander.write_register(Register::IodirB, 0xFF)?;
+        Ok(Self { expander })
+    }
+
+    /// Gives the expander back.
+    pub fn release(self) -> Mcp23017<I2C> {
+        self.expander
+    }
+
+    /// Scans the whole matrix, strobing one column at a time, and returns
+    /// which keys are held.
+    pub fn scan(&mut self) -> Result<MatrixState<R, C>, E> {
+        let mut state = MatrixState::new();
+        for col in 0..C {
+            self.expander.write_register(Register::OlatA, !(1 << col))?;
+            let rows = !self.expander.read_register(Register::GpioB)?;
+            for row in 0..R {
+                state.set(row, col, rows & (1 << row) != 0);
+            }
+        }
+        self.expander.write_register(Register::OlatA, 0xFF)?;
+        Ok(state)
+    }
+}
+
+impl<I2C, E, const R: usize, const C: usize> KeySource<R, C> for Mcp23017Matrix<I2C, R, C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    type Error = E;
+
+    fn scan<D: DelayUs<u32>>(&mut self, _: &mut D) -> Result<MatrixState<R, C>, E> {
+        Mcp23017Matrix::scan(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Transaction {
+        Write(u8, Vec<u8>),
+        WriteRead(u8, Vec<u8>),
+    }
+
+    /// An MCP23017 with a matrix on its ports, recording every
+    /// transaction.
+    #[derive(Default)]
+    struct Bus {
+        /// `(row, col)` of every held key.
+        keys: Vec<(usize, usize)>,
+        olat_a: u8,
+        log: Vec<Transaction>,
+    }
+
+    impl Write for Bus {
+        type Error = Infallible;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Infallible> {
+            if bytes[0] == Register::OlatA as u8 {
+                self.olat_a = bytes[1];
+            }
+            self.log.push(Transaction::Write(address, bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl WriteRead for Bus {
+        type Error = Infallible;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), Infallible> {
+            assert_eq!(bytes, [Register::GpioB as u8]);
+            // a row reads low through a held key on a column driven low
+            let mut rows = 0xFF;
+            for &(row, col) in &self.keys {
+                if self.olat_a & 1 << col == 0 {
+                    rows &= !(1 << row);
+                }
+            }
+            buffer[0] = rows;
+            self.log
+                .push(Transaction::WriteRead(address, bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn write(register: Register, value: u8) -> Transaction {
+        Transaction::Write(0x21, vec![register as u8, value])
+    }
+
+    #[test]
+    fn new_configures_the_ports() {
+        let matrix = Mcp23017Matrix::<_, 4, 6>::new(Mcp23017::new(Bus::default(), 0x21)).unwrap();
+        let bus = matrix.release().release();
+        assert_eq!(
+            bus.log,
+            [
+                write(Register::OlatA, 0xFF),
+                write(Register::GppuA, 0b1100_0000),
+                write(Register::IodirA, 0b1100_0000),
+                write(Register::GppuB, 0xFF),
+                write(Register::IodirB, 0xFF),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_strobes_each_column() {
+        let bus = Bus {
+            keys: vec![(0, 1), (3, 1), (2, 2)],
+            ..Bus::default()
+        };
+        let mut matrix = Mcp23017Matrix::<_, 4, 3>::new(Mcp23017::new(bus, 0x21)).unwrap();
+        let state = matrix.scan().unwrap();
+        assert_eq!(state, MatrixState::from_rows([0b010, 0, 0b100, 0b010]));
+
+        let bus = matrix.release().release();
+        let read = Transaction::WriteRead(0x21, vec![Register::GpioB as u8]);
+        assert_eq!(
+            bus.log[5..],
+            [
+                write(Register::OlatA, 0b1111_1110),
+                read.clone(),
+                write(Register::OlatA, 0b1111_1101),
+                read.clone(),
+                write(Register::OlatA, 0b1111_1011),
+                read,
+                write(Register::OlatA, 0xFF),
+            ]
+        );
+    }
+}
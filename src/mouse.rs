@@ -0,0 +1,338 @@
+//! Mouse keys: drive the pointer and wheels from key presses.
+//!
+//! `MouseKeys` is a pure state machine over a millisecond clock: keys
+//! are pressed and released with the time they happened at, and `tick`
+//! returns the `MouseReport` due at a given time, if any. Holding a
+//! movement key sends a first step right away, then after `Curve::delay`
+//! keeps sending steps every `Curve::interval`, speeding up from
+//! `Curve::start` to `Curve::max` over `Curve::time_to_max`.
+use crate::hid::{MouseButton, MouseReport};
+
+/// The keys of the mouse keys engine.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MouseKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    Button(MouseButton),
+}
+
+impl MouseKey {
+    fn bit(self) -> u16 {
+        match self {
+            MouseKey::Up => 1 << 0,
+            MouseKey::Down => 1 << 1,
+            MouseKey::Left => 1 << 2,
+            MouseKey::Right => 1 << 3,
+            MouseKey::WheelUp => 1 << 4,
+            MouseKey::WheelDown => 1 << 5,
+            MouseKey::WheelLeft => 1 << 6,
+            MouseKey::WheelRight => 1 << 7,
+            MouseKey::Button(button) => 1 << (8 + button as u8),
+        }
+    }
+}
+
+const MOVE_KEYS: u16 = 0x000F;
+const WHEEL_KEYS: u16 = 0x00F0;
+
+/// How the speed grows from `Curve::start` to `Curve::max`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ramp {
+    /// Speed grows at a constant rate.
+    Linear,
+    /// Speed grows slowly at first, for precise small movements, then
+    /// quickly towards the end.
+    Quadratic,
+}
+
+/// An acceleration curve, for either the pointer or the wheels.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Curve {
+    /// Time in ms between the first step and the start of the repeats.
+    pub delay: u16,
+    /// Time in ms between two repeated steps.
+    pub interval: u16,
+    /// Size of a step when the key is first pressed.
+    pub start: u8,
+    /// Size of a step at full speed, at most 127.
+    pub max: u8,
+    /// Time in ms from the first repeat until full speed is reached.
+    pub time_to_max: u16,
+    /// The shape of the acceleration.
+    pub ramp: Ramp,
+}
+
+impl Curve {
+    /// Returns the size of a step taken `elapsed` ms after the repeats
+    /// started.
+    pub fn speed(&self, elapsed: u32) -> u8 {
+        let (start, max) = (self.start as u32, self.max.min(127) as u32);
+        if elapsed >= self.time_to_max as u32 || max <= start {
+            return max as u8;
+        }
+        // progress towards full speed, out of 256
+        let progress = elapsed * 256 / self.time_to_max as u32;
+        let progress = match self.ramp {
+            Ramp::Linear => progress,
+            Ramp::Quadratic => progress * progress / 256,
+        };
+        (start + (max - start) * progress / 256) as u8
+    }
+}
+
+/// Configuration of the mouse keys engine.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MouseConfig {
+    /// Acceleration of the pointer.
+    pub movement: Curve,
+    /// Acceleration of both wheels.
+    pub wheel: Curve,
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self {
+            movement: Curve {
+                delay: 10,
+                interval: 16,
+                start: 4,
+                max: 40,
+                time_to_max: 500,
+                ramp: Ramp::Quadratic,
+            },
+            wheel: Curve {
+                delay: 200,
+                interval: 80,
+                start: 1,
+                max: 1,
+                time_to_max: 0,
+                ramp: Ramp::Linear,
+            },
+        }
+    }
+}
+
+/// Schedules the steps of a held group of keys along a `Curve`.
+#[derive(Debug, Clone, Copy, Default)]
+struct Repeat {
+    started: Option<u32>,
+    next: u32,
+}
+
+impl Repeat {
+    fn start(&mut self, now: u32) {
+        if self.started.is_none() {
+            self.started = Some(now);
+            self.next = now;
+        }
+    }
+
+    fn stop(&mut self) {
+        self.started = None;
+    }
+
+    /// Returns the size of the step due at `now`, if any.
+    fn step(&mut self, now: u32, curve: &Curve) -> Option<u8> {
+        let started = self.started?;
+        if (now.wrapping_sub(self.next) as i32) < 0 {
+            return None;
+        }
+        let elapsed = now.wrapping_sub(started);
+        let delay = curve.delay as u32;
+        if elapsed < delay {
+            self.next = started.wrapping_add(delay);
+            Some(curve.start.min(127))
+        } else {
+            self.next = now.wrapping_add((curve.interval as u32).max(1));
+            Some(curve.speed(elapsed - delay))
+        }
+    }
+}
+
+/// The mouse keys engine.
+#[derive(Debug, Clone)]
+pub struct MouseKeys {
+    config: MouseConfig,
+    held: u16,
+    sent_buttons: u8,
+    movement: Repeat,
+    wheel: Repeat,
+}
+
+impl MouseKeys {
+    /// Creates a new `MouseKeys` with no key held.
+    pub fn new(config: MouseConfig) -> Self {
+        Self {
+            config,
+            held: 0,
+            sent_buttons: 0,
+            movement: Repeat::default(),
+            wheel: Repeat::default(),
+        }
+    }
+
+    /// The given key was pressed at time `now`, in ms.
+    pub fn press(&mut self, key: MouseKey, now: u32) {
+        self.held |= key.bit();
+        if key.bit() & MOVE_KEYS != 0 {
+            self.movement.start(now);
+        }
+        if key.bit() & WHEEL_KEYS != 0 {
+            self.wheel.start(now);
+        }
+    }
+
+    /// The given key was released. Releasing the last held movement (or
+    /// wheel) key resets its acceleration.
+    pub fn release(&mut self, key: MouseKey) {
+        self.held &= !key.bit();
+        if self.held & MOVE_KEYS == 0 {
+            self.movement.stop();
+        }
+        if self.held & WHEEL_KEYS == 0 {
+            self.wheel.stop();
+        }
+    }
+
+    /// Returns `true` if the given key is held.
+    pub fn is_held(&self, key: MouseKey) -> bool {
+        self.held & key.bit() != 0
+    }
+
+    /// Returns the report to send at time `now`, in ms, if the buttons
+    /// changed or a step is due. This should be called at least as often
+    /// as the shortest `Curve::interval`.
+    pub fn tick(&mut self, now: u32) -> Option<MouseReport> {
+        let buttons = (self.held >> 8) as u8;
+        let mut report = MouseReport::default();
+        for button in [
+            MouseButton::Left,
+            MouseButton::Right,
+            MouseButton::Middle,
+            MouseButton::Back,
+            MouseButton::Forward,
+        ] {
+            if self.is_held(MouseKey::Button(button)) {
+                report.pressed(button);
+            }
+        }
+
+        let movement = self.movement.step(now, &self.config.movement);
+        if let Some(speed) = movement {
+            let x = self.direction(MouseKey::Left, MouseKey::Right);
+            let y = self.direction(MouseKey::Up, MouseKey::Down);
+            // keep diagonal moves at the same speed, scaling by ~1/sqrt(2)
+            let speed = if x != 0 && y != 0 {
+                (speed as u16 * 181 / 256).max(1) as i8
+            } else {
+                speed as i8
+            };
+            report.set_movement(x * speed, y * speed);
+        }
+
+        let wheel = self.wheel.step(now, &self.config.wheel);
+        if let Some(speed) = wheel {
+            let vertical = self.direction(MouseKey::WheelDown, MouseKey::WheelUp);
+            let horizontal = self.direction(MouseKey::WheelLeft, MouseKey::WheelRight);
+            report.set_wheel(vertical * speed as i8, horizontal * speed as i8);
+        }
+
+        if buttons == self.sent_buttons && movement.is_none() && wheel.is_none() {
+            return None;
+        }
+        self.sent_buttons = buttons;
+        Some(report)
+    }
+
+    /// Returns -1, 0 or 1 for a pair of opposite keys.
+    fn direction(&self, negative: MouseKey, positive: MouseKey) -> i8 {
+        self.is_held(positive) as i8 - self.is_held(negative) as i8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_step_is_sent_at_press_time() {
+        let mut mouse = MouseKeys::new(MouseConfig::default());
+        mouse.press(MouseKey::Right, 1000);
+        let report = mouse.tick(1000).unwrap();
+        assert_eq!(report.movement(), (4, 0));
+        assert_eq!(mouse.tick(1001), None);
+    }
+
+    #[test]
+    fn repeats_start_after_delay() {
+        let config = MouseConfig::default();
+        let movement = config.movement;
+        let mut mouse = MouseKeys::new(config);
+        mouse.press(MouseKey::Down, 0);
+        mouse.tick(0).unwrap();
+        for now in 1..movement.delay as u32 {
+            assert_eq!(mouse.tick(now), None, "t={}", now);
+        }
+        let first = movement.delay as u32;
+        assert_eq!(mouse.tick(first).unwrap().movement(), (0, 4));
+        let second = first + movement.interval as u32;
+        assert_eq!(mouse.tick(second - 1), None);
+        assert!(mouse.tick(second).is_some());
+    }
+
+    #[test]
+    fn speed_reaches_max_at_time_to_max() {
+        for ramp in [Ramp::Linear, Ramp::Quadratic] {
+            let curve = Curve {
+                ramp,
+                ..MouseConfig::default().movement
+            };
+            let time_to_max = curve.time_to_max as u32;
+            assert_eq!(curve.speed(0), curve.start, "{:?}", ramp);
+            assert!(curve.speed(time_to_max - 1) < curve.max, "{:?}", ramp);
+            assert_eq!(curve.speed(time_to_max), curve.max, "{:?}", ramp);
+            assert_eq!(curve.speed(time_to_max * 2), curve.max, "{:?}", ramp);
+        }
+        // the quadratic ramp is slower to start
+        let linear = Curve {
+            ramp: Ramp::Linear,
+            ..MouseConfig::default().movement
+        };
+        let quadratic = MouseConfig::default().movement;
+        assert!(quadratic.speed(250) < linear.speed(250));
+    }
+
+    #[test]
+    fn diagonal_moves_are_scaled() {
+        let mut config = MouseConfig::default();
+        config.movement.start = 40;
+        let mut mouse = MouseKeys::new(config);
+        mouse.press(MouseKey::Up, 0);
+        mouse.press(MouseKey::Left, 0);
+        // 40 / sqrt(2), rounded down
+        assert_eq!(mouse.tick(0).unwrap().movement(), (-28, -28));
+    }
+
+    #[test]
+    fn button_change_sends_one_report() {
+        let mut mouse = MouseKeys::new(MouseConfig::default());
+        mouse.press(MouseKey::Button(MouseButton::Left), 0);
+        let report = mouse.tick(0).unwrap();
+        assert_eq!(report.buttons(), 0b1);
+        assert_eq!(report.movement(), (0, 0));
+        assert_eq!(report.wheel(), (0, 0));
+        for now in 1..1000 {
+            assert_eq!(mouse.tick(now), None, "t={}", now);
+        }
+
+        mouse.release(MouseKey::Button(MouseButton::Left));
+        assert_eq!(mouse.tick(1000).unwrap().buttons(), 0);
+        assert_eq!(mouse.tick(1001), None);
+    }
+}
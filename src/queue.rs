@@ -0,0 +1,223 @@
+//! Transmit queue for HID reports.
+//!
+//! The firmware rebuilds its reports from the key state on every pass of
+//! the main loop, much faster than the host polls the endpoints. Pushing
+//! each of them would flood the endpoint, and dropping the ones refused
+//! with `UsbError::WouldBlock` can lose a whole tap when its press and
+//! release both happen between two polls.
+//!
+//! `ReportQueue` sits between the two: it only keeps reports that differ
+//! from the one before them, and hands them to a `ReportSink` in order,
+//! keeping each one until the sink accepts it.
+//!
+//! Only use it for reports that describe a state, like keyboard or
+//! consumer reports. Two identical relative reports, like mouse
+//! movements, are not a duplicate.
+use heapless::Deque;
+use usb_device::UsbError;
+
+/// Somewhere to send reports, usually one of the interfaces of
+/// `usb::Composite`. Any `FnMut(&R) -> usb_device::Result<usize>` is a
+/// sink, which makes it easy to wrap a shared interface, or to fake one.
+pub trait ReportSink<R> {
+    /// Tries to send `report`, failing with `UsbError::WouldBlock` when
+    /// the endpoint is still busy with the previous one.
+    fn send(&mut self, report: &R) -> usb_device::Result<usize>;
+}
+
+impl<R, F> ReportSink<R> for F
+where
+    F: FnMut(&R) -> usb_device::Result<usize>,
+{
+    fn send(&mut self, report: &R) -> usb_device::Result<usize> {
+        self(report)
+    }
+}
+
+/// A queue of up to `N` reports waiting to be sent, in order.
+#[derive(Debug, Clone)]
+pub struct ReportQueue<R, const N: usize> {
+    pending: Deque<R, N>,
+    sent: Option<R>,
+}
+
+impl<R, const N: usize> Default for ReportQueue<R, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R, const N: usize> ReportQueue<R, N> {
+    /// Creates an empty queue, which has sent nothing yet.
+    pub const fn new() -> Self {
+        Self {
+            pending: Deque::new(),
+            sent: None,
+        }
+    }
+
+    /// Returns the number of reports waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns `true` if every report has been sent.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the last report accepted by the sink, if any.
+    pub fn last_sent(&self) -> Option<&R> {
+        self.sent.as_ref()
+    }
+
+    /// Forgets the last sent report, so that the next one is queued even
+    /// if it is the same. Use this when the host may have lost track of
+    /// it, like after a protocol switch or a bus reset.
+    pub fn invalidate(&mut self) {
+        self.sent = None;
+    }
+
+    /// Drops every pending report and forgets the last sent one.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.sent = None;
+    }
+}
+
+impl<R: Clone + PartialEq, const N: usize> ReportQueue<R, N> {
+    /// Queues `report`, unless it is the same as the last queued report,
+    /// or the last sent one when nothing is pending.
+    ///
+    /// When the queue is full the report is handed back: the caller can
+    /// simply push its current state again on its next pass, once
+    /// `flush` has made room.
+    pub fn push(&mut self, report: R) -> Result<(), R> {
+        if self.pending.back().or(self.sent.as_ref()) == Some(&report) {
+            return Ok(());
+        }
+        self.pending.push_back(report)
+    }
+
+    /// Sends pending reports to `sink`, oldest first, until the queue is
+    /// empty or the sink refuses one. A report refused with
+    /// `UsbError::WouldBlock` stays at the front of the queue, to be
+    /// retried on the next call. Any other error is returned, also
+    /// leaving the report queued.
+    pub fn flush<S: ReportSink<R>>(&mut self, sink: &mut S) -> usb_device::Result<()> {
+        while let Some(report) = self.pending.front() {
+            match sink.send(report) {
+                Ok(_) => self.sent = self.pending.pop_front(),
+                Err(UsbError::WouldBlock) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sink that is busy until `free` is set, then takes one report.
+    struct Endpoint {
+        sent: Vec<u8>,
+        free: bool,
+    }
+
+    impl Endpoint {
+        fn new() -> Self {
+            Self {
+                sent: Vec::new(),
+                free: false,
+            }
+        }
+
+        fn sink(&mut self) -> impl FnMut(&u8) -> usb_device::Result<usize> + '_ {
+            |report| match core::mem::take(&mut self.free) {
+                true => {
+                    self.sent.push(*report);
+                    Ok(1)
+                }
+                false => Err(UsbError::WouldBlock),
+            }
+        }
+
+        /// The host polls, then the queue is flushed.
+        fn poll(&mut self, queue: &mut ReportQueue<u8, 4>) {
+            self.free = true;
+            queue.flush(&mut self.sink()).unwrap();
+        }
+    }
+
+    #[test]
+    fn tap_between_polls_is_sent_in_order() {
+        let mut endpoint = Endpoint::new();
+        let mut queue = ReportQueue::<u8, 4>::new();
+        queue.push(1).unwrap();
+        queue.flush(&mut endpoint.sink()).unwrap();
+        queue.push(0).unwrap();
+        queue.flush(&mut endpoint.sink()).unwrap();
+        assert_eq!(queue.len(), 2);
+        endpoint.poll(&mut queue);
+        endpoint.poll(&mut queue);
+        assert_eq!(endpoint.sent, [1, 0]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.last_sent(), Some(&0));
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut endpoint = Endpoint::new();
+        let mut queue = ReportQueue::<u8, 4>::new();
+        for report in [1, 1, 2, 2, 2, 1] {
+            queue.push(report).unwrap();
+        }
+        assert_eq!(queue.len(), 3);
+        while !queue.is_empty() {
+            endpoint.poll(&mut queue);
+        }
+        // nor is the last sent report queued again
+        queue.push(1).unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(endpoint.sent, [1, 2, 1]);
+    }
+
+    #[test]
+    fn invalidate_sends_again() {
+        let mut endpoint = Endpoint::new();
+        let mut queue = ReportQueue::<u8, 4>::new();
+        queue.push(3).unwrap();
+        endpoint.poll(&mut queue);
+        queue.invalidate();
+        queue.push(3).unwrap();
+        endpoint.poll(&mut queue);
+        assert_eq!(endpoint.sent, [3, 3]);
+    }
+
+    #[test]
+    fn errors_keep_the_report() {
+        let mut queue = ReportQueue::<u8, 4>::new();
+        queue.push(5).unwrap();
+        let mut fail = |_: &u8| Err(UsbError::InvalidState);
+        assert!(matches!(
+            queue.flush(&mut fail),
+            Err(UsbError::InvalidState)
+        ));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.last_sent(), None);
+        let mut endpoint = Endpoint::new();
+        endpoint.poll(&mut queue);
+        assert_eq!(endpoint.sent, [5]);
+    }
+
+    #[test]
+    fn full_queue_hands_the_report_back() {
+        let mut queue = ReportQueue::<u8, 4>::new();
+        for report in 0..4 {
+            queue.push(report).unwrap();
+        }
+        assert_eq!(queue.push(9), Err(9));
+    }
+}
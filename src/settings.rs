@@ -0,0 +1,83 @@
+//! Settings kept across power cycles.
+//!
+//! `Settings` are stored as a small record starting with a magic number
+//! and a version, and ending with a checksum, so that erased flash (all
+//! 0xFF), or a record from another firmware, reads as the defaults.
+//! Where the record lives is up to the board, see `main.rs`.
+use crate::hid::ReportMode;
+
+/// Length in bytes of a stored `Settings` record.
+pub const SETTINGS_LEN: usize = 8;
+
+const MAGIC: [u8; 4] = *b"keeb";
+const VERSION: u8 = 1;
+
+/// The settings the user can change at run time.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Settings {
+    /// The keyboard reports sent in the REPORT protocol.
+    pub report_mode: ReportMode,
+}
+
+impl Settings {
+    /// Returns the record storing these settings.
+    pub fn to_bytes(&self) -> [u8; SETTINGS_LEN] {
+        let mut bytes = [0; SETTINGS_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.report_mode as u8;
+        bytes[SETTINGS_LEN - 1] = checksum(&bytes[..SETTINGS_LEN - 1]);
+        bytes
+    }
+
+    /// Reads the settings back from a record, or returns `None` if
+    /// `bytes` does not start with a valid one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..SETTINGS_LEN)?;
+        if bytes[..4] != MAGIC
+            || bytes[4] != VERSION
+            || bytes[SETTINGS_LEN - 1] != checksum(&bytes[..SETTINGS_LEN - 1])
+        {
+            return None;
+        }
+        Some(Self {
+            report_mode: ReportMode::from_u8(bytes[5])?,
+        })
+    }
+}
+
+/// The checksum of a record: the complement of the sum of its bytes.
+fn checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for report_mode in [ReportMode::Nkro, ReportMode::SixKro] {
+            let settings = Settings { report_mode };
+            assert_eq!(Settings::from_bytes(&settings.to_bytes()), Some(settings));
+        }
+    }
+
+    #[test]
+    fn erased_flash_is_none() {
+        assert_eq!(Settings::from_bytes(&[0xFF; 4096]), None);
+    }
+
+    #[test]
+    fn bad_checksum_is_none() {
+        let mut bytes = Settings::default().to_bytes();
+        bytes[SETTINGS_LEN - 1] ^= 1;
+        assert_eq!(Settings::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn short_record_is_none() {
+        let bytes = Settings::default().to_bytes();
+        assert_eq!(Settings::from_bytes(&bytes[..SETTINGS_LEN - 1]), None);
+    }
+}
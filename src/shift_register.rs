@@ -0,0 +1,346 @@
+//! Key matrix scanning through shift registers.
+//!
+//! Boards with more keys than GPIOs drive the rows of their matrix from
+//! a chain of 74HC595 (serial in, parallel out) and read its columns
+//! with a chain of 74HC165 (parallel in, serial out), with four pins
+//! whatever the size of the matrix:
+//!
+//! * latch: RCLK of the 595s and SH/LD of the 165s. Idle high; pulsing it
+//!   low loads the 165s with their inputs, and its rising edge moves the
+//!   bits shifted into the 595s to their outputs.
+//! * clock: SRCLK of the 595s and CLK of the 165s, idle low, both shifting
+//!   on its rising edge.
+//! * data out: SER of the first 595.
+//! * data in: QH of the first 165.
+//!
+//! Row `r` is output `Q(r % 8)` of the `r / 8`th 595 of the chain, and
+//! column `c` input `D(c % 8)` of the `c / 8`th 165, counting from the
+//! chips wired to the data pins. Like a COL2ROW `Matrix`, rows are
+//! strobed low one at a time, and columns read low through the diodes
+//! when their key on the strobed row is down, so they need pull-ups.
+//!
+//! Since the latch is shared, each row takes two latch pulses: the first
+//! drives the new strobe pattern, while the 165s load stale inputs, and
+//! the second loads the inputs once they settled, while the 595s latch
+//! the same pattern again. Clocking the 165s also shifts the 595s, which
+//! is harmless as every row shifts a whole new pattern before latching.
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::matrix::{KeySource, MatrixState};
+
+/// A scanner for an `R`×`C` key matrix behind shift registers.
+pub struct ShiftRegisterMatrix<I, O, const R: usize, const C: usize> {
+    latch: O,
+    clock: O,
+    data_out: O,
+    data_in: I,
+    pulse_us: u32,
+    settle_us: u32,
+}
+
+/// Number of bits shifted through a chain of 8-bit registers with at
+/// least `n` outputs or inputs.
+const fn chain_bits(n: usize) -> usize {
+    n.div_ceil(8) * 8
+}
+
+impl<I, O, E, const R: usize, const C: usize> ShiftRegisterMatrix<I, O, R, C>
+where
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+{
+    /// Pulse width used unless set with `with_pulse_width`.
+    pub const DEFAULT_PULSE_US: u32 = 1;
+
+    /// Settle delay used unless set with `with_settle_delay`.
+    pub const DEFAULT_SETTLE_US: u32 = 10;
+
+    /// Creates a scanner on the given pins, see the module documentation,
+    /// and puts the latch and clock to their idle levels.
+    pub fn new(mut latch: O, mut clock: O, data_out: O, data_in: I) -> Result<Self, E> {
+        latch.set_high()?;
+        clock.set_low()?;
+        Ok(Self {
+            latch,
+            clock,
+            data_out,
+            data_in,
+            pulse_us: Self::DEFAULT_PULSE_US,
+            settle_us: Self::DEFAULT_SETTLE_US,
+        })
+    }
+
+    /// Sets how long, in µs, the clock and latch stay at each level of a
+    /// pulse. 74HC parts only need tens of ns, so this is mostly for long
+    /// or slow wiring.
+    pub fn with_pulse_width(mut self, pulse_us: u32) -> Self {
+        self.pulse_us = pulse_us;
+        self
+    }
+
+    /// Sets the time to wait, in µs, between strobing a row and loading
+    /// the inputs, long enough for the columns to settle through the
+    /// pull-ups and the wiring.
+    pub fn with_settle_delay(mut self, settle_us: u32) -> Self {
+        self.settle_us = settle_us;
+        self
+    }
+
+    fn pulse_clock<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<(), E> {
+        self.clock.set_high()?;
+        delay.delay_us(self.pulse_us);
+        self.clock.set_low()?;
+        delay.delay_us(self.pulse_us);
+        Ok(())
+    }
+
+    fn pulse_latch<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<(), E> {
+        self.latch.set_low()?;
+        delay.delay_us(self.pulse_us);
+        self.latch.set_high()?;
+        delay.delay_us(self.pulse_us);
+        Ok(())
+    }
+
+    /// Shifts a strobe pattern into the 595s and latches it: `row` low,
+    /// or none with `None`, and every other output high.
+    fn strobe<D: DelayUs<u32>>(&mut self, row: Option<usize>, delay: &mut D) -> Result<(), E> {
+        // the first bit shifted ends up on the last output of the chain
+        for output in (0..chain_bits(R)).rev() {
+            match Some(output) == row {
+                true => self.data_out.set_low()?,
+                false => self.data_out.set_high()?,
+            }
+            self.pulse_clock(delay)?;
+        }
+        self.pulse_latch(delay)
+    }
+
+    /// Loads the 165s and shifts their inputs out, returning the bits of
+    /// the columns that read low.
+    fn read_columns<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<u32, E> {
+        self.pulse_latch(delay)?;
+        let mut cols = 0;
+        // each 165 shifts out its input D7 first
+        for bit in 0..chain_bits(C) {
+            let col = bit / 8 * 8 + 7 - bit % 8;
+            if col < C && self.data_in.is_low()? {
+                cols |= 1 << col;
+            }
+            self.pulse_clock(delay)?;
+        }
+        Ok(cols)
+    }
+
+    /// Scans the whole matrix, strobing one row at a time, and returns
+    /// which keys are held.
+    pub fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, E> {
+        let mut rows = [0; R];
+        for (row, bits) in rows.iter_mut().enumerate() {
+            self.strobe(Some(row), delay)?;
+            delay.delay_us(self.settle_us);
+            *bits = self.read_columns(delay)?;
+        }
+        self.strobe(None, delay)?;
+        Ok(MatrixState::from_rows(rows))
+    }
+}
+
+impl<I, O, E, const R: usize, const C: usize> KeySource<R, C> for ShiftRegisterMatrix<I, O, R, C>
+where
+    I: InputPin<Error = E>,
+    O: OutputPin<Error = E>,
+{
+    type Error = E;
+
+    fn scan<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<MatrixState<R, C>, E> {
+        ShiftRegisterMatrix::scan(self, delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use std::rc::Rc;
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    enum Pin {
+        Latch,
+        Clock,
+        DataOut,
+    }
+
+    /// A chain of 595s and 165s wired to a matrix, recording every level
+    /// set on its pins.
+    #[derive(Default)]
+    struct Chips {
+        /// `(row, col)` of every held key.
+        keys: Vec<(usize, usize)>,
+        log: Vec<(Pin, bool)>,
+        data_out: bool,
+        /// The 595 shift register, output 0 in bit 0.
+        shifted: u32,
+        /// The 595 outputs, as latched at each rising edge of the latch.
+        latched: Vec<u32>,
+        /// The column bits loaded into the 165s, and how many were
+        /// shifted out since.
+        loaded: Vec<bool>,
+        position: usize,
+    }
+
+    impl Chips {
+        fn set(&mut self, pin: Pin, high: bool) {
+            let was_high = self
+                .log
+                .iter()
+                .rev()
+                .find(|(p, _)| *p == pin)
+                .map_or(pin != Pin::Clock, |(_, high)| *high);
+            self.log.push((pin, high));
+            match (pin, was_high, high) {
+                (Pin::DataOut, _, _) => self.data_out = high,
+                (Pin::Clock, false, true) => {
+                    // each 595 output takes the one before it, the first
+                    // the data pin
+                    self.shifted = self.shifted << 1 | self.data_out as u32;
+                    self.position += 1;
+                }
+                (Pin::Latch, true, false) => self.load(),
+                (Pin::Latch, false, true) => self.latched.push(self.shifted),
+                _ => {}
+            }
+        }
+
+        /// Loads the 165s: every column reads low through the held keys
+        /// of the rows the 595 outputs drive low. Each 165 shifts out its
+        /// D7 input first.
+        fn load(&mut self) {
+            let outputs = self.latched.last().copied().unwrap_or(u32::MAX);
+            let low = |col: usize| {
+                self.keys
+                    .iter()
+                    .any(|&(row, c)| c == col && outputs & 1 << row == 0)
+            };
+            self.loaded = (0..32).map(|bit| !low(bit / 8 * 8 + 7 - bit % 8)).collect();
+            self.position = 0;
+        }
+    }
+
+    type Shared = Rc<RefCell<Chips>>;
+
+    struct Output(Pin, Shared);
+
+    impl OutputPin for Output {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().set(self.0, false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().set(self.0, true);
+            Ok(())
+        }
+    }
+
+    struct Input(Shared);
+
+    impl InputPin for Input {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            let chips = self.0.borrow();
+            Ok(chips.loaded.get(chips.position).copied().unwrap_or(true))
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayUs<u32> for NoDelay {
+        fn delay_us(&mut self, _: u32) {}
+    }
+
+    fn matrix<const R: usize, const C: usize>(
+        chips: &Shared,
+    ) -> ShiftRegisterMatrix<Input, Output, R, C> {
+        ShiftRegisterMatrix::new(
+            Output(Pin::Latch, chips.clone()),
+            Output(Pin::Clock, chips.clone()),
+            Output(Pin::DataOut, chips.clone()),
+            Input(chips.clone()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn strobe_is_shifted_last_output_first() {
+        let chips = Shared::default();
+        let mut matrix = matrix::<10, 4>(&chips);
+        matrix.scan(&mut NoDelay).unwrap();
+
+        let chips = chips.borrow();
+        // the data out level at each rising clock edge before the first
+        // latch pulse: row 0 is output 0, so its low bit comes last
+        let mut clock = false;
+        let mut data = true;
+        let mut shifted = vec![];
+        let strobe = chips
+            .log
+            .iter()
+            .take_while(|step| **step != (Pin::Latch, false));
+        for &(pin, high) in strobe {
+            match pin {
+                Pin::DataOut => data = high,
+                Pin::Clock if high && !clock => shifted.push(data),
+                _ => {}
+            }
+            if pin == Pin::Clock {
+                clock = high;
+            }
+        }
+        let mut expected = vec![true; chain_bits(10)];
+        expected[chain_bits(10) - 1] = false;
+        assert_eq!(shifted, expected);
+    }
+
+    #[test]
+    fn two_latch_pulses_per_row() {
+        let chips = Shared::default();
+        let mut matrix = matrix::<10, 4>(&chips);
+        matrix.scan(&mut NoDelay).unwrap();
+
+        let mask = (1 << chain_bits(10)) - 1;
+        let mut expected = vec![];
+        for row in 0..10 {
+            // the strobe, then the same pattern again while loading
+            expected.extend([!(1 << row) & mask; 2]);
+        }
+        expected.push(mask);
+        let chips = chips.borrow();
+        let latched: Vec<u32> = chips.latched.iter().map(|bits| bits & mask).collect();
+        assert_eq!(latched, expected);
+    }
+
+    #[test]
+    fn columns_are_read_d7_first() {
+        let keys = [(0, 0), (0, 7), (1, 3), (1, 8), (2, 11), (2, 4)];
+        let chips = Shared::default();
+        chips.borrow_mut().keys = keys.to_vec();
+        let mut matrix = matrix::<3, 12>(&chips);
+        let state = matrix.scan(&mut NoDelay).unwrap();
+
+        let mut expected = [0u32; 3];
+        for (row, col) in keys {
+            expected[row] |= 1 << col;
+        }
+        assert_eq!(state, MatrixState::from_rows(expected));
+    }
+}
@@ -0,0 +1,726 @@
+//! Split keyboards: the link between the two halves.
+//!
+//! Both halves run the same firmware, and the one powered through its USB
+//! port becomes the primary (see `Role`): it talks to the host, and reads
+//! the keys of the other half, the secondary, from a UART. Which half is
+//! on the left is up to a strap pin instead (see `Side`), so that either
+//! one can be plugged in. The secondary
+//! only scans and debounces its keys, and its `Sender` sends
+//!
+//! * an event for every key that changed, as soon as it changes,
+//! * and its whole state every `SYNC_INTERVAL_MS`, changed or not.
+//!
+//! On the primary, a `Receiver` applies them to its copy of the
+//! secondary's keys, which `RemoteHalf` hands to `matrix::Halves` like any
+//! other key source.
+//!
+//! A frame is a sequence number, a kind, a payload and the CRC-16 of all
+//! three, COBS encoded so that it has no zero byte, then a zero. Noise or
+//! a lost byte make a frame fail its CRC, and the receiver starts over
+//! after the next zero. The sequence numbers tell it when whole frames
+//! were lost: it still applies the events that follow, which are newer,
+//! and the next state that gets through repairs whatever the lost ones
+//! changed, within `SYNC_INTERVAL_MS` unless it is lost too. The states
+//! are also the heartbeat: without a frame for `LINK_TIMEOUT_MS`, the
+//! secondary is gone and its keys are released.
+use core::convert::Infallible;
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::serial::Read;
+
+use crate::matrix::{KeySource, MatrixState};
+
+/// How often the secondary sends its whole state, in ms.
+pub const SYNC_INTERVAL_MS: u32 = 100;
+
+/// How long the primary waits for a frame before releasing the keys of
+/// the secondary, in ms.
+pub const LINK_TIMEOUT_MS: u32 = 3 * SYNC_INTERVAL_MS;
+
+/// Rows a half can have.
+pub const MAX_ROWS: usize = 16;
+
+/// Length of the longest frame: sequence number, kind, the state of
+/// `MAX_ROWS` rows of 32 columns, CRC, the COBS overhead and the zero.
+pub const MAX_FRAME_LEN: usize = {
+    let raw = 2 + MAX_ROWS * 4 + 2;
+    raw + raw.div_ceil(254) + 1
+};
+
+const EVENT: u8 = 0x01;
+const STATE: u8 = 0x02;
+
+/// Which end of the link a half is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Role {
+    /// Connected to the host, reading the keys of the other half.
+    Primary,
+    /// Sending its keys to the primary.
+    Secondary,
+}
+
+impl Role {
+    /// Returns the role of a half from whether its USB port is powered,
+    /// like GPIO 24 of a Pico tells. This only picks the half that talks
+    /// to the host, not where its keys are, see `Side`.
+    pub fn from_vbus(vbus: bool) -> Self {
+        match vbus {
+            true => Role::Primary,
+            false => Role::Secondary,
+        }
+    }
+}
+
+/// Which half of the keyboard a half is: the left one has the first half
+/// of the columns of the keymap, the right one the second half.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Returns the side of a half from its strap pin, read with a
+    /// pull-up: left when left open, right when tied to ground.
+    pub fn from_strap(grounded: bool) -> Self {
+        match grounded {
+            true => Side::Right,
+            false => Side::Left,
+        }
+    }
+
+    /// Returns `keys`, read with the columns of this half first, with
+    /// the columns of the left half first, as the keymap has them.
+    pub fn left_first<const R: usize, const C: usize>(
+        self,
+        keys: &MatrixState<R, C>,
+    ) -> MatrixState<R, C> {
+        const { assert!(C.is_multiple_of(2), "the halves must have the same width") };
+        let half = C / 2;
+        let mut rows = *keys.rows();
+        if self == Side::Right {
+            let mask = (1 << half) - 1;
+            for row in &mut rows {
+                *row = (*row >> half) | ((*row & mask) << half);
+            }
+        }
+        MatrixState::from_rows(rows)
+    }
+}
+
+/// What a frame tells the primary about the keys of an `R`×`C` half.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Message<const R: usize, const C: usize> {
+    /// The key at `row`, `col` was pressed or released.
+    Event {
+        row: usize,
+        col: usize,
+        pressed: bool,
+    },
+    /// The keys held.
+    State(MatrixState<R, C>),
+}
+
+/// A numbered `Message`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Frame<const R: usize, const C: usize> {
+    /// Counts the frames sent, wrapping around.
+    pub seq: u8,
+    pub message: Message<R, C>,
+}
+
+/// Why received bytes are not a frame.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FrameError {
+    /// More bytes than `MAX_FRAME_LEN` without a zero.
+    TooLong,
+    /// Bytes that COBS did not produce.
+    Encoding,
+    /// A CRC that does not match, most likely after noise or lost bytes.
+    Checksum,
+    /// A valid CRC over an unknown kind, a payload of the wrong length,
+    /// or a key outside the half: the halves run different firmware.
+    Malformed,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameError::TooLong => "frame too long",
+            FrameError::Encoding => "invalid COBS encoding",
+            FrameError::Checksum => "CRC mismatch",
+            FrameError::Malformed => "malformed frame",
+        })
+    }
+}
+
+impl<const R: usize, const C: usize> Frame<R, C> {
+    const FITS: () = assert!(R <= MAX_ROWS, "a half has at most MAX_ROWS rows");
+
+    /// Bytes of each row in a state payload.
+    const ROW_LEN: usize = C.div_ceil(8);
+
+    /// Encodes the frame into `buf`, returning the bytes to send, the
+    /// final zero included.
+    pub fn encode<'a>(&self, buf: &'a mut [u8; MAX_FRAME_LEN]) -> &'a [u8] {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        let mut raw = [0; MAX_FRAME_LEN];
+        raw[0] = self.seq;
+        let mut len = 2;
+        match self.message {
+            Message::Event { row, col, pressed } => {
+                raw[1] = EVENT;
+                raw[2..5].copy_from_slice(&[row as u8, col as u8, pressed as u8]);
+                len += 3;
+            }
+            Message::State(keys) => {
+                raw[1] = STATE;
+                for bits in keys.rows() {
+                    raw[len..len + Self::ROW_LEN]
+                        .copy_from_slice(&bits.to_le_bytes()[..Self::ROW_LEN]);
+                    len += Self::ROW_LEN;
+                }
+            }
+        }
+        let crc = crc16(&raw[..len]);
+        raw[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+        len += 2;
+        let end = cobs_encode(&raw[..len], &mut buf[..]);
+        buf[end] = 0;
+        &buf[..=end]
+    }
+
+    /// Decodes a frame from the bytes received before a zero.
+    pub fn decode(encoded: &[u8]) -> Result<Self, FrameError> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        let mut raw = [0; MAX_FRAME_LEN];
+        let len = cobs_decode(encoded, &mut raw).ok_or(FrameError::Encoding)?;
+        if len < 4 {
+            return Err(FrameError::Malformed);
+        }
+        let (data, crc) = raw[..len].split_at(len - 2);
+        if crc16(data) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return Err(FrameError::Checksum);
+        }
+        let message = match (data[1], &data[2..]) {
+            (EVENT, &[row, col, pressed @ (0 | 1)]) if (row as usize) < R && (col as usize) < C => {
+                Message::Event {
+                    row: row as usize,
+                    col: col as usize,
+                    pressed: pressed == 1,
+                }
+            }
+            (STATE, payload) if payload.len() == R * Self::ROW_LEN => {
+                let mut rows = [0; R];
+                for (bits, bytes) in rows.iter_mut().zip(payload.chunks(Self::ROW_LEN)) {
+                    let mut le = [0; 4];
+                    le[..Self::ROW_LEN].copy_from_slice(bytes);
+                    *bits = u32::from_le_bytes(le);
+                    if *bits & !u32::MAX.checked_shr(32 - C as u32).unwrap_or(0) != 0 {
+                        return Err(FrameError::Malformed);
+                    }
+                }
+                Message::State(MatrixState::from_rows(rows))
+            }
+            _ => return Err(FrameError::Malformed),
+        };
+        Ok(Self {
+            seq: data[0],
+            message,
+        })
+    }
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, starting from 0xFFFF.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// COBS encodes `data` into `out`, returning the length written. `out`
+/// must have room for one more byte every 254, plus one.
+fn cobs_encode(data: &[u8], out: &mut [u8]) -> usize {
+    // each block starts with the distance to the next zero, the zero
+    // itself being left out
+    let (mut code_at, mut len, mut code) = (0, 1, 1u8);
+    for &byte in data {
+        if byte != 0 {
+            out[len] = byte;
+            len += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            out[code_at] = code;
+            (code_at, len, code) = (len, len + 1, 1);
+        }
+    }
+    out[code_at] = code;
+    len
+}
+
+/// Decodes COBS encoded `data` into `out`, returning the length written,
+/// or `None` if it is not valid or does not fit.
+fn cobs_decode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let (mut at, mut len) = (0, 0);
+    while at < data.len() {
+        let code = data[at] as usize;
+        let block = data.get(at + 1..at + code)?;
+        out.get_mut(len..len + block.len())?.copy_from_slice(block);
+        len += block.len();
+        at += code;
+        if code != 0xFF && at < data.len() {
+            *out.get_mut(len)? = 0;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// Splits received bytes into frames at the zeros.
+#[derive(Debug, Clone)]
+pub struct Decoder<const R: usize, const C: usize> {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<const R: usize, const C: usize> Default for Decoder<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> Decoder<R, C> {
+    /// Creates a decoder waiting for the start of a frame.
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Takes the next received byte, returning the frame, or why there is
+    /// none, when it ends one.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<R, C>, FrameError>> {
+        if byte != 0 {
+            match self.buf.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                }
+                None => self.overflowed = true,
+            }
+            return None;
+        }
+        let frame = match (self.overflowed, self.len) {
+            (true, _) => Some(Err(FrameError::TooLong)),
+            // zeros between frames
+            (false, 0) => None,
+            (false, len) => Some(Frame::decode(&self.buf[..len])),
+        };
+        self.len = 0;
+        self.overflowed = false;
+        frame
+    }
+}
+
+/// What a `Receiver` has seen of the link.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LinkStats {
+    /// Valid frames received.
+    pub frames: u32,
+    /// Received bytes that were not a valid frame, counted once for each
+    /// zero ending them.
+    pub errors: u32,
+    /// Frames never received, from the gaps in the sequence numbers.
+    pub lost: u32,
+}
+
+/// The primary's end of the link, keeping track of the keys of an `R`×`C`
+/// secondary half.
+#[derive(Debug, Clone)]
+pub struct Receiver<const R: usize, const C: usize> {
+    decoder: Decoder<R, C>,
+    keys: MatrixState<R, C>,
+    next_seq: Option<u8>,
+    stats: LinkStats,
+}
+
+impl<const R: usize, const C: usize> Default for Receiver<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> Receiver<R, C> {
+    /// Creates a receiver with no key held.
+    pub fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            keys: MatrixState::new(),
+            next_seq: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Takes the next received byte, applying the frame it ends, if any.
+    pub fn push(&mut self, byte: u8) {
+        match self.decoder.push(byte) {
+            Some(Ok(frame)) => self.apply(frame),
+            Some(Err(_)) => self.stats.errors += 1,
+            None => {}
+        }
+    }
+
+    fn apply(&mut self, frame: Frame<R, C>) {
+        self.stats.frames += 1;
+        if let Some(next) = self.next_seq {
+            self.stats.lost += frame.seq.wrapping_sub(next) as u32;
+        }
+        self.next_seq = Some(frame.seq.wrapping_add(1));
+        match frame.message {
+            Message::Event { row, col, pressed } => self.keys.set(row, col, pressed),
+            Message::State(keys) => self.keys = keys,
+        }
+    }
+
+    /// Returns the keys held on the secondary, as far as we know.
+    pub fn keys(&self) -> MatrixState<R, C> {
+        self.keys
+    }
+
+    /// Returns what was received so far.
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Releases every key, when the secondary is gone. The next state it
+    /// sends presses them again.
+    pub fn release_all(&mut self) {
+        self.keys = MatrixState::new();
+    }
+}
+
+/// The secondary's end of the link.
+#[derive(Debug, Clone)]
+pub struct Sender<const R: usize, const C: usize> {
+    seq: u8,
+    keys: MatrixState<R, C>,
+    last_sync: Option<u32>,
+}
+
+impl<const R: usize, const C: usize> Default for Sender<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> Sender<R, C> {
+    /// Creates a sender that sends the state with its first `update`.
+    pub fn new() -> Self {
+        Self {
+            seq: 0,
+            keys: MatrixState::new(),
+            last_sync: None,
+        }
+    }
+
+    /// Numbers `message` and encodes it into `buf`, returning the bytes
+    /// to send.
+    pub fn encode<'a>(
+        &mut self,
+        message: Message<R, C>,
+        buf: &'a mut [u8; MAX_FRAME_LEN],
+    ) -> &'a [u8] {
+        let frame = Frame {
+            seq: self.seq,
+            message,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        frame.encode(buf)
+    }
+
+    /// Hands `send` the frames telling the primary about the debounced
+    /// `keys` at `now`, in ms: an event for every change since the last
+    /// update, and the whole state if it was not sent for
+    /// `SYNC_INTERVAL_MS`.
+    pub fn update(&mut self, keys: &MatrixState<R, C>, now: u32, mut send: impl FnMut(&[u8])) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let previous = core::mem::replace(&mut self.keys, *keys);
+        for (row, col, pressed) in keys.changes(&previous) {
+            send(self.encode(Message::Event { row, col, pressed }, &mut buf));
+        }
+        if self
+            .last_sync
+            .is_none_or(|last| now.wrapping_sub(last) >= SYNC_INTERVAL_MS)
+        {
+            send(self.encode(Message::State(*keys), &mut buf));
+            self.last_sync = Some(now);
+        }
+    }
+}
+
+/// The keys of the secondary half, read from the serial port its frames
+/// come in on, as a key source of the primary.
+pub struct RemoteHalf<S, const R: usize, const C: usize> {
+    serial: S,
+    receiver: Receiver<R, C>,
+    seen_frames: u32,
+    last_frame: Option<u32>,
+}
+
+impl<S, const R: usize, const C: usize> RemoteHalf<S, R, C>
+where
+    S: Read<u8>,
+{
+    /// Creates the secondary's half, reading its frames from `serial`,
+    /// with no key held.
+    pub fn new(serial: S) -> Self {
+        Self {
+            serial,
+            receiver: Receiver::new(),
+            seen_frames: 0,
+            last_frame: None,
+        }
+    }
+
+    /// Gives the serial port back.
+    pub fn release(self) -> S {
+        self.serial
+    }
+
+    /// Applies the frames received since the last call, and returns the
+    /// keys held on the secondary.
+    pub fn scan(&mut self) -> MatrixState<R, C> {
+        // read errors lose the byte, which the CRC then catches
+        while let Ok(byte) = self.serial.read() {
+            self.receiver.push(byte);
+        }
+        self.receiver.keys()
+    }
+
+    /// Releases the keys of the secondary if no frame came for
+    /// `LINK_TIMEOUT_MS` before `now`, in ms. Meant to be called once for
+    /// each scan.
+    pub fn expire(&mut self, now: u32) {
+        let frames = self.receiver.stats().frames;
+        match self.last_frame {
+            Some(last) if frames == self.seen_frames => {
+                if now.wrapping_sub(last) >= LINK_TIMEOUT_MS {
+                    self.receiver.release_all();
+                }
+            }
+            _ => {
+                self.seen_frames = frames;
+                self.last_frame = Some(now);
+            }
+        }
+    }
+
+    /// Returns what was received so far.
+    pub fn stats(&self) -> LinkStats {
+        self.receiver.stats()
+    }
+}
+
+impl<S, const R: usize, const C: usize> KeySource<R, C> for RemoteHalf<S, R, C>
+where
+    S: Read<u8>,
+{
+    type Error = Infallible;
+
+    fn scan<D: DelayUs<u32>>(&mut self, _: &mut D) -> Result<MatrixState<R, C>, Infallible> {
+        Ok(RemoteHalf::scan(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// The frames `sender` sends for `keys` at `now`.
+    fn frames(sender: &mut Sender<2, 3>, keys: &MatrixState<2, 3>, now: u32) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        sender.update(keys, now, |frame| frames.push(frame.to_vec()));
+        frames
+    }
+
+    fn receive(receiver: &mut Receiver<2, 3>, frames: &[Vec<u8>]) {
+        for byte in frames.iter().flatten() {
+            receiver.push(*byte);
+        }
+    }
+
+    fn held(keys: &[(usize, usize)]) -> MatrixState<2, 3> {
+        let mut state = MatrixState::new();
+        for &(row, col) in keys {
+            state.set(row, col, true);
+        }
+        state
+    }
+
+    #[test]
+    fn sender_to_receiver() {
+        let mut sender = Sender::new();
+        let mut receiver = Receiver::new();
+        // the first update sends the keys pressed, then the state
+        let keys = held(&[(0, 1), (1, 2)]);
+        receive(&mut receiver, &frames(&mut sender, &keys, 0));
+        assert_eq!(receiver.keys(), keys);
+
+        // then the changes, as events
+        let keys = held(&[(1, 2), (1, 0)]);
+        let sent = frames(&mut sender, &keys, 10);
+        assert_eq!(sent.len(), 2);
+        receive(&mut receiver, &sent);
+        assert_eq!(receiver.keys(), keys);
+        assert_eq!(
+            receiver.stats(),
+            LinkStats {
+                frames: 5,
+                errors: 0,
+                lost: 0
+            }
+        );
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        let frame = Frame::<2, 3> {
+            seq: 5,
+            message: Message::Event {
+                row: 1,
+                col: 2,
+                pressed: true,
+            },
+        };
+        let mut buf = [0; MAX_FRAME_LEN];
+        let mut encoded = frame.encode(&mut buf).to_vec();
+        // a single COBS block: its code, then the bytes of the frame
+        assert_eq!(encoded[0] as usize, encoded.len() - 1);
+        // the column
+        encoded[4] ^= 0x01;
+
+        let mut decoder = Decoder::<2, 3>::new();
+        let (last, bytes) = encoded.split_last().unwrap();
+        assert!(bytes.iter().all(|byte| decoder.push(*byte).is_none()));
+        assert_eq!(decoder.push(*last), Some(Err(FrameError::Checksum)));
+    }
+
+    #[test]
+    fn dropped_byte_resyncs_on_next_zero() {
+        let mut sender = Sender::new();
+        let mut decoder = Decoder::<2, 3>::new();
+        frames(&mut sender, &held(&[]), 0);
+        let mut first = frames(&mut sender, &held(&[(0, 0)]), 10).remove(0);
+        let second = frames(&mut sender, &held(&[]), 20).remove(0);
+        first.remove(3);
+
+        let results: Vec<_> = first
+            .iter()
+            .chain(&second)
+            .filter_map(|byte| decoder.push(*byte))
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert_eq!(
+            results[1],
+            Ok(Frame {
+                seq: 2,
+                message: Message::Event {
+                    row: 0,
+                    col: 0,
+                    pressed: false
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn lost_event_is_repaired_by_next_state() {
+        let mut sender = Sender::new();
+        let mut receiver = Receiver::new();
+        receive(&mut receiver, &frames(&mut sender, &held(&[]), 0));
+
+        let keys = held(&[(1, 1)]);
+        let lost = frames(&mut sender, &keys, 10);
+        assert_eq!(lost.len(), 1);
+        assert_eq!(receiver.keys(), held(&[]));
+
+        let sync = frames(&mut sender, &keys, SYNC_INTERVAL_MS);
+        receive(&mut receiver, &sync);
+        assert_eq!(receiver.keys(), keys);
+        assert_eq!(receiver.stats().lost, 1);
+    }
+
+    /// A serial port handing out the bytes queued in it.
+    struct Serial(Rc<RefCell<VecDeque<u8>>>);
+
+    impl Read<u8> for Serial {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.0.borrow_mut().pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn keys_expire_without_frames() {
+        let bytes = Rc::new(RefCell::new(VecDeque::new()));
+        let mut remote = RemoteHalf::new(Serial(bytes.clone()));
+        let mut sender = Sender::new();
+        let keys = held(&[(0, 2)]);
+        bytes
+            .borrow_mut()
+            .extend(frames(&mut sender, &keys, 0).concat());
+
+        assert_eq!(remote.scan(), keys);
+        remote.expire(1000);
+        remote.expire(1000 + LINK_TIMEOUT_MS - 1);
+        assert_eq!(remote.scan(), keys);
+        remote.expire(1000 + LINK_TIMEOUT_MS);
+        assert_eq!(remote.scan(), held(&[]));
+
+        // until the secondary is back
+        bytes
+            .borrow_mut()
+            .extend(frames(&mut sender, &keys, SYNC_INTERVAL_MS).concat());
+        assert_eq!(remote.scan(), keys);
+    }
+
+    #[test]
+    fn strap_picks_the_side() {
+        assert_eq!(Side::from_strap(false), Side::Left);
+        assert_eq!(Side::from_strap(true), Side::Right);
+        // whichever half has the host
+        assert_eq!(Role::from_vbus(true), Role::Primary);
+        assert_eq!(Role::from_vbus(false), Role::Secondary);
+    }
+
+    #[test]
+    fn right_primary_puts_its_columns_second() {
+        // the local half's columns first, as `Halves` reads them
+        let keys = MatrixState::<2, 4>::from_rows([0b0001, 0b1100]);
+        assert_eq!(Side::Left.left_first(&keys), keys);
+        assert_eq!(
+            Side::Right.left_first(&keys),
+            MatrixState::from_rows([0b0100, 0b0011])
+        );
+    }
+}
@@ -0,0 +1,616 @@
+//! Scripted switch timelines, and running them through the pipeline.
+//!
+//! A trace is a list of steps, separated by `;` or new lines, each
+//! pressing or releasing one or more switches at a time in ms:
+//!
+//! ```text
+//! t=0 press 2,3; t=40 release 2,3
+//! # comments run to the end of the line
+//! t=100 press 0,0 0,1
+//! ```
+//!
+//! `run` feeds such a timeline to the same debounce and layout pipeline
+//! as the firmware, one scan per ms, and hands back every keyboard
+//! report it produces. This lets keymaps and timing sensitive behaviors
+//! be checked on the host, without a board.
+//!
+//! `check` goes one step further and compares the reports to the ones
+//! listed in a golden trace, byte for byte. The golden traces of the
+//! repo live in `traces/`, see `traces/check.sh`; the tests of this
+//! module check every one of them too, with the keymap build.rs
+//! generates from the `.toml` file next to it.
+use core::fmt;
+
+use crate::debounce::Debouncer;
+use crate::hid::{NKROReport, NKRO_REPORT_LEN};
+use crate::layout::Keyboard;
+use crate::matrix::MatrixState;
+
+/// A switch pressed or released at a point of a trace.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SwitchEvent {
+    /// Time of the event, in ms.
+    pub time: u32,
+    /// `true` for a press, `false` for a release.
+    pub pressed: bool,
+    /// Row of the switch.
+    pub row: usize,
+    /// Column of the switch.
+    pub col: usize,
+}
+
+/// What can be wrong with a trace.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TraceError<'a> {
+    /// A step that is not `t=<ms> press|release <row>,<col>...`.
+    Syntax(&'a str),
+    /// A step happening before the one preceding it.
+    OutOfOrder(&'a str),
+    /// An event on a switch outside of the matrix.
+    OutOfRange(SwitchEvent),
+}
+
+impl fmt::Display for TraceError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Syntax(step) => write!(
+                f,
+                "`{}`: expected `t=<ms> press|release <row>,<col>...`",
+                step
+            ),
+            TraceError::OutOfOrder(step) => {
+                write!(f, "`{}`: happens before the previous step", step)
+            }
+            TraceError::OutOfRange(event) => write!(
+                f,
+                "t={}: switch {},{} is not on the matrix",
+                event.time, event.row, event.col
+            ),
+        }
+    }
+}
+
+/// The events of a trace, in order.
+#[derive(Debug, Clone)]
+pub struct Trace<'a> {
+    lines: core::str::Lines<'a>,
+    steps: core::str::Split<'a, char>,
+    /// The step being read, its time and kind, and its remaining switches.
+    step: Option<(&'a str, u32, bool, core::str::SplitAsciiWhitespace<'a>)>,
+    time: u32,
+}
+
+impl<'a> Trace<'a> {
+    /// Reads the trace in `text`.
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            lines: text.lines(),
+            steps: "".split(';'),
+            step: None,
+            time: 0,
+        }
+    }
+
+    /// Returns the next non-empty step, skipping comments.
+    fn next_step(&mut self) -> Option<&'a str> {
+        loop {
+            if let Some(step) = self.steps.next() {
+                let step = step.trim();
+                if !step.is_empty() {
+                    return Some(step);
+                }
+                continue;
+            }
+            let line = self.lines.next()?;
+            let line = line.split('#').next().unwrap_or_default();
+            self.steps = line.split(';');
+        }
+    }
+
+    fn start_step(&mut self, step: &'a str) -> Result<(), TraceError<'a>> {
+        let mut words = step.split_ascii_whitespace();
+        let time = words
+            .next()
+            .and_then(|word| word.strip_prefix("t="))
+            .and_then(|time| time.parse().ok())
+            .ok_or(TraceError::Syntax(step))?;
+        let pressed = match words.next() {
+            Some("press") => true,
+            Some("release") => false,
+            _ => return Err(TraceError::Syntax(step)),
+        };
+        if time < self.time {
+            return Err(TraceError::OutOfOrder(step));
+        }
+        self.time = time;
+        self.step = Some((step, time, pressed, words));
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Trace<'a> {
+    type Item = Result<SwitchEvent, TraceError<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((step, time, pressed, switches)) = &mut self.step {
+                let (step, time, pressed) = (*step, *time, *pressed);
+                if let Some(switch) = switches.next() {
+                    let event = switch
+                        .split_once(',')
+                        .and_then(|(row, col)| Some((row.parse().ok()?, col.parse().ok()?)))
+                        .map(|(row, col)| SwitchEvent {
+                            time,
+                            pressed,
+                            row,
+                            col,
+                        });
+                    if event.is_none() {
+                        self.step = None;
+                    }
+                    return Some(event.ok_or(TraceError::Syntax(step)));
+                }
+                self.step = None;
+            }
+            let step = self.next_step()?;
+            if let Err(err) = self.start_step(step) {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// Runs the events of `trace` through `debouncer` and `keyboard`, one
+/// scan per ms from time 0 to `end`, calling `report` with the time and
+/// content of every keyboard report that differs from the previous one.
+/// The first report is compared to an empty one.
+pub fn run<'a, D, T, const R: usize, const C: usize>(
+    trace: Trace<'a>,
+    end: u32,
+    debouncer: &mut D,
+    keyboard: &mut Keyboard<T, R, C>,
+    mut report: impl FnMut(u32, &NKROReport),
+) -> Result<(), TraceError<'a>>
+where
+    D: Debouncer<R, C> + ?Sized,
+{
+    let mut trace = trace.peekable();
+    let mut raw = MatrixState::<R, C>::new();
+    let mut sent = NKROReport::default();
+    for now in 0..=end {
+        while let Some(event) = trace.next_if(|event| match event {
+            Ok(event) => event.time <= now,
+            Err(_) => true,
+        }) {
+            let event = event?;
+            if event.row >= R || event.col >= C {
+                return Err(TraceError::OutOfRange(event));
+            }
+            raw.set(event.row, event.col, event.pressed);
+        }
+
+        keyboard.update(&debouncer.update(&raw, now), now);
+        let current = keyboard.report();
+        if current != sent {
+            report(now, &current);
+            sent = current;
+        }
+    }
+    Ok(())
+}
+
+/// Time a golden trace keeps running after its last event or report, so
+/// that late reports, like the ones of an expiring hold, are caught.
+pub const GOLDEN_TAIL: u32 = 1000;
+
+/// A report expected by a golden trace: its time, and its bytes.
+pub type ExpectedReport = (u32, [u8; NKRO_REPORT_LEN]);
+
+/// What can be wrong with a golden trace, or with the reports it produced.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GoldenError<'a> {
+    /// The trace itself is wrong.
+    Trace(TraceError<'a>),
+    /// A line of expected reports that is not `t=<ms> <bytes>`, or a
+    /// golden trace without a `---` line.
+    Expected(&'a str),
+    /// The `index`th report is not the expected one. Either of them is
+    /// missing when there are more reports on the other side.
+    Mismatch {
+        index: usize,
+        expected: Option<ExpectedReport>,
+        actual: Option<ExpectedReport>,
+    },
+}
+
+impl<'a> From<TraceError<'a>> for GoldenError<'a> {
+    fn from(err: TraceError<'a>) -> Self {
+        GoldenError::Trace(err)
+    }
+}
+
+/// Writes a report in the format of golden traces.
+struct Hex<'a>(&'a ExpectedReport);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t={}", self.0 .0)?;
+        self.0
+             .1
+            .iter()
+            .try_for_each(|byte| write!(f, " {:02x}", byte))
+    }
+}
+
+impl fmt::Display for GoldenError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Trace(err) => err.fmt(f),
+            GoldenError::Expected(line) => {
+                write!(
+                    f,
+                    "`{}`: expected `t=<ms> <{} hex bytes>`",
+                    line, NKRO_REPORT_LEN
+                )
+            }
+            GoldenError::Mismatch {
+                index,
+                expected,
+                actual,
+            } => {
+                writeln!(f, "report {} differs", index)?;
+                match expected {
+                    Some(expected) => writeln!(f, "  expected: {}", Hex(expected))?,
+                    None => writeln!(f, "  expected: nothing")?,
+                }
+                match actual {
+                    Some(actual) => write!(f, "    actual: {}", Hex(actual)),
+                    None => write!(f, "    actual: nothing"),
+                }
+            }
+        }
+    }
+}
+
+/// Writes `report` at `time` as a line of expected reports of a golden
+/// trace, which is how they are usually made: run the trace once, check
+/// the reports by hand, and paste them in.
+pub fn write_golden(f: &mut impl fmt::Write, time: u32, report: &NKROReport) -> fmt::Result {
+    let mut bytes = [0; NKRO_REPORT_LEN];
+    bytes.copy_from_slice(report.as_bytes());
+    write!(f, "{}", Hex(&(time, bytes)))
+}
+
+/// The expected reports of a golden trace, in order.
+struct Expected<'a>(core::str::Lines<'a>);
+
+impl<'a> Iterator for Expected<'a> {
+    type Item = Result<ExpectedReport, GoldenError<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self
+            .0
+            .by_ref()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .find(|line| !line.is_empty())?;
+        Some(parse_expected(line).ok_or(GoldenError::Expected(line)))
+    }
+}
+
+fn parse_expected(line: &str) -> Option<ExpectedReport> {
+    let mut words = line.split_ascii_whitespace();
+    let time = words.next()?.strip_prefix("t=")?.parse().ok()?;
+    let mut bytes = [0; NKRO_REPORT_LEN];
+    let mut len = 0;
+    for word in words {
+        *bytes.get_mut(len)? = u8::from_str_radix(word, 16).ok()?;
+        len += 1;
+    }
+    (len == NKRO_REPORT_LEN).then_some((time, bytes))
+}
+
+/// Runs a golden trace through `debouncer` and `keyboard`, and checks
+/// that it produces exactly the expected keyboard reports.
+///
+/// A golden trace is a trace, a line with `---`, then the expected
+/// reports, one per line, as their time and every byte in hex:
+///
+/// ```text
+/// t=0 press 0,0; t=50 release 0,0
+/// ---
+/// t=1 00 00 04 00 00 00 00 00 10 00 ... # A
+/// t=56 00 00 00 00 00 00 00 00 00 00 ...
+/// ```
+///
+/// It runs until `GOLDEN_TAIL` ms after its last event or expected
+/// report, so a report that was not expected at the end fails as well.
+pub fn check<'a, D, T, const R: usize, const C: usize>(
+    golden: &'a str,
+    debouncer: &mut D,
+    keyboard: &mut Keyboard<T, R, C>,
+) -> Result<(), GoldenError<'a>>
+where
+    D: Debouncer<R, C> + ?Sized,
+{
+    let mut offset = 0;
+    let (trace, expected) = golden
+        .split_inclusive('\n')
+        .find_map(|line| {
+            offset += line.len();
+            (line.trim() == "---").then(|| (&golden[..offset - line.len()], &golden[offset..]))
+        })
+        .ok_or(GoldenError::Expected("missing `---` line"))?;
+
+    let mut end = 0;
+    for event in Trace::new(trace) {
+        end = end.max(event?.time);
+    }
+    for report in Expected(expected.lines()) {
+        end = end.max(report?.0);
+    }
+
+    let mut expected = Expected(expected.lines());
+    let mut index = 0;
+    let mut mismatch = None;
+    run(
+        Trace::new(trace),
+        end.saturating_add(GOLDEN_TAIL),
+        debouncer,
+        keyboard,
+        |time, report| {
+            let mut bytes = [0; NKRO_REPORT_LEN];
+            bytes.copy_from_slice(report.as_bytes());
+            let actual = (time, bytes);
+            // the expected reports were all parsed above
+            let expected = expected.next().and_then(Result::ok);
+            if mismatch.is_none() && expected != Some(actual) {
+                mismatch = Some(GoldenError::Mismatch {
+                    index,
+                    expected,
+                    actual: Some(actual),
+                });
+            }
+            index += 1;
+        },
+    )?;
+    if let Some(mismatch) = mismatch {
+        return Err(mismatch);
+    }
+    match expected.next().and_then(Result::ok) {
+        Some(expected) => Err(GoldenError::Mismatch {
+            index,
+            expected: Some(expected),
+            actual: None,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::SymmetricDefer;
+    use crate::layout::Custom;
+    use keyberon::action::Action::KeyCode as K;
+    use keyberon::key_code::KeyCode;
+    use keyberon::layout::Layers;
+
+    /// Checks the `GOLDEN` trace of the module it is used in against the
+    /// keymap items next to it.
+    macro_rules! golden_test {
+        () => {
+            #[test]
+            fn golden() {
+                let mut debouncer = KeyDebouncer::<ROWS, COLS>::new(DEBOUNCE_MS);
+                let mut keyboard = crate::layout::Keyboard::new(LAYERS, CUSTOM_ACTIONS);
+                if let Err(err) = crate::trace::check(GOLDEN, &mut debouncer, &mut keyboard) {
+                    panic!("{}", err);
+                }
+            }
+        };
+    }
+
+    // a module for each of the golden traces of `traces/`
+    include!(concat!(env!("OUT_DIR"), "/traces.rs"));
+
+    static LAYERS: Layers = &[&[&[K(KeyCode::A), K(KeyCode::B)]]];
+
+    fn event(time: u32, pressed: bool, row: usize, col: usize) -> SwitchEvent {
+        SwitchEvent {
+            time,
+            pressed,
+            row,
+            col,
+        }
+    }
+
+    /// Runs `trace` on `LAYERS` without debouncing delay, and returns its
+    /// reports with the key codes of each.
+    fn run_trace(trace: &str) -> Result<Vec<(u32, Vec<KeyCode>)>, TraceError<'_>> {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(LAYERS, &[]);
+        let mut reports = Vec::new();
+        run(
+            Trace::new(trace),
+            100,
+            &mut SymmetricDefer::new(0),
+            &mut keyboard,
+            |time, report| reports.push((time, report.pressed_keys().collect())),
+        )?;
+        Ok(reports)
+    }
+
+    #[test]
+    fn steps_and_comments() {
+        let trace = "t=0 press 0,0 0,1 # both\n\n  t=5 release 0,1;t=5 release 0,0;\n# done";
+        assert_eq!(
+            Trace::new(trace).collect::<Vec<_>>(),
+            [
+                Ok(event(0, true, 0, 0)),
+                Ok(event(0, true, 0, 1)),
+                Ok(event(5, false, 0, 1)),
+                Ok(event(5, false, 0, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_steps() {
+        for step in [
+            "press 0,0",
+            "t=x press 0,0",
+            "t=0 push 0,0",
+            "t=0",
+            "t=0 press 0",
+            "t=0 press a,0",
+            "t=0 press 0,-1",
+        ] {
+            let events = Trace::new(step).collect::<Vec<_>>();
+            assert_eq!(events, [Err(TraceError::Syntax(step))], "{}", step);
+        }
+    }
+
+    #[test]
+    fn bad_step_after_good_switches() {
+        let trace = "t=0 press 0,0 0,x; t=1 release 0,0";
+        assert_eq!(
+            Trace::new(trace).collect::<Vec<_>>(),
+            [
+                Ok(event(0, true, 0, 0)),
+                Err(TraceError::Syntax("t=0 press 0,0 0,x")),
+                Ok(event(1, false, 0, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_order_steps() {
+        let trace = "t=10 press 0,0\nt=5 release 0,0";
+        assert_eq!(
+            Trace::new(trace).collect::<Vec<_>>(),
+            [
+                Ok(event(10, true, 0, 0)),
+                Err(TraceError::OutOfOrder("t=5 release 0,0")),
+            ]
+        );
+        assert_eq!(
+            run_trace(trace),
+            Err(TraceError::OutOfOrder("t=5 release 0,0"))
+        );
+    }
+
+    #[test]
+    fn out_of_range_switches() {
+        assert_eq!(
+            run_trace("t=0 press 0,0; t=3 press 1,0"),
+            Err(TraceError::OutOfRange(event(3, true, 1, 0)))
+        );
+        assert_eq!(
+            run_trace("t=3 press 0,2"),
+            Err(TraceError::OutOfRange(event(3, true, 0, 2)))
+        );
+    }
+
+    #[test]
+    fn run_reports_changes() {
+        let reports = run_trace("t=0 press 0,0; t=10 press 0,1; t=20 release 0,0 0,1");
+        assert_eq!(
+            reports,
+            Ok(vec![
+                (1, vec![KeyCode::A]),
+                (11, vec![KeyCode::A, KeyCode::B]),
+                (21, vec![KeyCode::B]),
+                (22, vec![]),
+            ])
+        );
+    }
+
+    /// A golden trace of `LAYERS` pressing A from t=1 to t=21.
+    fn golden_a(reports: &str) -> String {
+        let mut golden = String::from("t=0 press 0,0; t=20 release 0,0\n---\n");
+        for (time, key) in [(1, KeyCode::A), (21, KeyCode::No)] {
+            let report: NKROReport = [key].into_iter().collect();
+            write_golden(&mut golden, time, &report).unwrap();
+            golden.push('\n');
+        }
+        golden + reports
+    }
+
+    fn check_golden(golden: &str) -> Result<(), GoldenError<'_>> {
+        let mut keyboard: Keyboard<Custom, 1, 2> = Keyboard::new(LAYERS, &[]);
+        check(golden, &mut SymmetricDefer::new(0), &mut keyboard)
+    }
+
+    #[test]
+    fn write_golden_bytes() {
+        let report: NKROReport = [KeyCode::A].into_iter().collect();
+        let mut line = String::new();
+        write_golden(&mut line, 7, &report).unwrap();
+        let mut expected = String::from("t=7 00 00 04 00 00 00 00 00 10");
+        expected += &" 00".repeat(NKRO_REPORT_LEN - 9);
+        assert_eq!(line, expected);
+    }
+
+    #[test]
+    fn check_accepts_its_own_reports() {
+        assert_eq!(check_golden(&golden_a("")), Ok(()));
+        assert_eq!(check_golden(&golden_a("# comment\n\n")), Ok(()));
+    }
+
+    #[test]
+    fn check_mismatches() {
+        let golden = golden_a("");
+        let (head, tail) = golden.split_once("t=21").unwrap();
+        let late = format!("{}t=22{}", head, tail);
+        let err = check_golden(&late).unwrap_err();
+        assert!(matches!(
+            err,
+            GoldenError::Mismatch {
+                index: 1,
+                expected: Some((22, _)),
+                actual: Some((21, _)),
+            }
+        ));
+        assert!(err.to_string().starts_with("report 1 differs\n"));
+
+        // a report expected after the last one
+        let extra = golden_a("t=500 00 00 04 00 00 00 00 00 10");
+        let extra = extra + &" 00".repeat(NKRO_REPORT_LEN - 9);
+        assert!(matches!(
+            check_golden(&extra),
+            Err(GoldenError::Mismatch {
+                index: 2,
+                expected: Some((500, _)),
+                actual: None,
+            })
+        ));
+
+        // a report missing from the expected ones
+        let (missing, _) = golden.split_once("t=21").unwrap();
+        assert!(matches!(
+            check_golden(missing),
+            Err(GoldenError::Mismatch {
+                index: 1,
+                expected: None,
+                actual: Some((21, _)),
+            })
+        ));
+    }
+
+    #[test]
+    fn check_bad_goldens() {
+        assert_eq!(
+            check_golden("t=0 press 0,0"),
+            Err(GoldenError::Expected("missing `---` line"))
+        );
+        assert_eq!(
+            check_golden("t=0 press 0,0\n---\nt=1 00 00 04"),
+            Err(GoldenError::Expected("t=1 00 00 04"))
+        );
+        assert_eq!(
+            check_golden("t=0 press 0,5\n---\n"),
+            Err(GoldenError::Trace(TraceError::OutOfRange(event(
+                0, true, 0, 5
+            ))))
+        );
+    }
+}
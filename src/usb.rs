@@ -0,0 +1,494 @@
+//! USB class implementations for our HID interfaces.
+//!
+//! usbd-hid's `HIDClass` refuses to send input from a boot interface
+//! unless the host has selected the BOOT protocol, which rules out a
+//! single keyboard interface that switches between our BOOT and NKRO
+//! reports. `KeyboardClass` handles the HID class requests itself, and
+//! leaves protocol tracking and report encoding to `hid::ProtocolState`.
+//!
+//! `Composite` puts every interface of the device together. Each kind of
+//! report gets its own interface and endpoints rather than sharing one
+//! multi-collection descriptor, which some hosts handle poorly, and each
+//! interface can be left out with its cargo feature:
+//!
+//! * `boot-keyboard`: a `KeyboardClass`, sending BOOT or NKRO reports.
+//! * `nkro-keyboard`: an NKRO only keyboard. With `boot-keyboard` also
+//!   enabled, the boot interface only speaks the BOOT protocol, and only
+//!   sends reports when the host selected it, or in 6KRO mode.
+//! * `consumer`: Consumer and System Control reports.
+//! * `mouse`: mouse reports.
+//! * `raw-hid`: a vendor defined interface for host tools.
+//!
+//! The keyboard reports can be switched to 6KRO at run time, see
+//! `hid::ReportMode`. With both keyboards, that only moves the reports
+//! to the boot interface. With the boot keyboard alone, its report
+//! descriptor changes, which the host only picks up when the device
+//! enumerates again. Without the boot keyboard, there is no 6KRO.
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::device::UsbDevice;
+use usb_device::UsbError;
+#[cfg(any(
+    feature = "nkro-keyboard",
+    feature = "consumer",
+    feature = "mouse",
+    feature = "raw-hid"
+))]
+use usbd_hid::hid_class::HIDClass;
+
+use crate::hid::{self, HostLeds, MouseReport, NKROReport, Protocol, ProtocolState, ReportMode};
+
+const INTERFACE_CLASS_HID: u8 = 0x03;
+const SUBCLASS_BOOT: u8 = 0x01;
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+const REQUEST_GET_REPORT: u8 = 0x01;
+const REQUEST_GET_IDLE: u8 = 0x02;
+const REQUEST_GET_PROTOCOL: u8 = 0x03;
+const REQUEST_SET_REPORT: u8 = 0x09;
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+
+const REPORT_TYPE_INPUT: u8 = 0x01;
+const REPORT_TYPE_OUTPUT: u8 = 0x02;
+
+/// Max packet size of the interrupt endpoints; enough for a whole
+/// `NKROReport` in a single transaction.
+const MAX_PACKET_SIZE: u16 = 64;
+
+/// A boot keyboard interface sending `NKROReport`s, encoded for
+/// whichever protocol the host has selected, and receiving the host's
+/// LED state.
+pub struct KeyboardClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint_in: EndpointIn<'a, B>,
+    endpoint_out: EndpointOut<'a, B>,
+    protocol: ProtocolState,
+    leds: HostLeds,
+    /// The last report pushed, for GET_REPORT.
+    report: NKROReport,
+    /// The idle rate set by the host, in units of 4 ms, for GET_IDLE.
+    /// Reports are only ever sent on change, whatever it is.
+    idle: u8,
+    boot_only: bool,
+}
+
+impl<B: UsbBus> KeyboardClass<'_, B> {
+    /// Creates a new `KeyboardClass`, allocating its interface and
+    /// interrupt IN and OUT endpoints, polled every `poll_ms` milliseconds.
+    pub fn new(alloc: &UsbBusAllocator<B>, poll_ms: u8) -> KeyboardClass<'_, B> {
+        KeyboardClass {
+            interface: alloc.interface(),
+            endpoint_in: alloc.interrupt(MAX_PACKET_SIZE, poll_ms),
+            endpoint_out: alloc.interrupt(MAX_PACKET_SIZE, poll_ms),
+            protocol: ProtocolState::default(),
+            leds: HostLeds::default(),
+            report: NKROReport::default(),
+            idle: 0,
+            boot_only: false,
+        }
+    }
+
+    /// Creates a new `KeyboardClass` that describes itself with
+    /// `hid::BOOT_REPORT_DESCRIPTOR`, and always sends 8-byte BOOT
+    /// reports. This is for devices with a separate NKRO interface.
+    pub fn boot_only(alloc: &UsbBusAllocator<B>, poll_ms: u8) -> KeyboardClass<'_, B> {
+        KeyboardClass {
+            boot_only: true,
+            ..Self::new(alloc, poll_ms)
+        }
+    }
+
+    /// Returns the protocol currently selected by the host.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.protocol()
+    }
+
+    /// Returns the reports sent in the REPORT protocol.
+    pub fn mode(&self) -> ReportMode {
+        self.protocol.mode()
+    }
+
+    /// Select the reports sent in the REPORT protocol. Returns `true` if
+    /// that changed the report descriptor, which the host only reads
+    /// again when the device enumerates. A `boot_only` keyboard always
+    /// sends BOOT reports, and ignores this.
+    pub fn set_mode(&mut self, mode: ReportMode) -> bool {
+        if self.boot_only || mode == self.mode() {
+            return false;
+        }
+        self.protocol.set_mode(mode);
+        true
+    }
+
+    /// Returns the LED state last sent by the host, either on the
+    /// interrupt OUT endpoint or with a SET_REPORT request.
+    pub fn host_leds(&self) -> HostLeds {
+        self.leds
+    }
+
+    /// Tries to send `report`, encoded for the current protocol. It is
+    /// kept for GET_REPORT requests either way.
+    pub fn push_report(&mut self, report: &NKROReport) -> usb_device::Result<usize> {
+        self.report = report.clone();
+        self.endpoint_in.write(self.encode(report))
+    }
+
+    fn encode<'r>(&self, report: &'r NKROReport) -> &'r [u8] {
+        match self.boot_only {
+            true => &report.as_bytes()[..hid::BOOT_REPORT_LEN],
+            false => self.protocol.encode(report),
+        }
+    }
+
+    fn report_descriptor(&self) -> &'static [u8] {
+        match self.boot_only {
+            true => hid::BOOT_REPORT_DESCRIPTOR,
+            false => self.protocol.mode().report_descriptor(),
+        }
+    }
+
+    fn is_ours(&self, request: &Request) -> bool {
+        request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for KeyboardClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.interface,
+            INTERFACE_CLASS_HID,
+            SUBCLASS_BOOT,
+            PROTOCOL_KEYBOARD,
+        )?;
+        writer.write(
+            DESCRIPTOR_TYPE_HID,
+            &hid_descriptor(self.report_descriptor())[2..],
+        )?;
+        writer.endpoint(&self.endpoint_in)?;
+        writer.endpoint(&self.endpoint_out)
+    }
+
+    fn reset(&mut self) {
+        self.protocol.reset();
+        self.leds = HostLeds::default();
+        self.idle = 0;
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.endpoint_out.address() {
+            return;
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE as usize];
+        if let Ok(len) = self.endpoint_out.read(&mut buf) {
+            if let Some(leds) = HostLeds::from_report(&buf[..len]) {
+                self.leds = leds;
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_ours(&req) {
+            return;
+        }
+
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match req.descriptor_type_index() {
+                (DESCRIPTOR_TYPE_REPORT, 0) => {
+                    xfer.accept_with_static(self.report_descriptor()).ok();
+                }
+                (DESCRIPTOR_TYPE_HID, 0) => {
+                    xfer.accept_with(&hid_descriptor(self.report_descriptor()))
+                        .ok();
+                }
+                _ => (),
+            },
+            (RequestType::Class, REQUEST_GET_REPORT)
+                if (req.value >> 8) as u8 == REPORT_TYPE_INPUT =>
+            {
+                xfer.accept_with(self.encode(&self.report)).ok();
+            }
+            (RequestType::Class, REQUEST_GET_IDLE) => {
+                xfer.accept_with(&[self.idle]).ok();
+            }
+            (RequestType::Class, REQUEST_GET_PROTOCOL) => {
+                xfer.accept_with(&[self.protocol() as u8]).ok();
+            }
+            (RequestType::Class, _) => {
+                xfer.reject().ok();
+            }
+            _ => (),
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !(self.is_ours(&req) && req.request_type == RequestType::Class) {
+            return;
+        }
+
+        match req.request {
+            REQUEST_SET_PROTOCOL => match self.protocol.set_protocol(req.value) {
+                Some(_) => xfer.accept().ok(),
+                None => xfer.reject().ok(),
+            },
+            REQUEST_SET_REPORT if (req.value >> 8) as u8 == REPORT_TYPE_OUTPUT => {
+                match HostLeds::from_report(xfer.data()) {
+                    Some(leds) => {
+                        self.leds = leds;
+                        xfer.accept().ok()
+                    }
+                    None => xfer.reject().ok(),
+                }
+            }
+            REQUEST_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                xfer.accept().ok()
+            }
+            _ => xfer.reject().ok(),
+        };
+    }
+}
+
+/// The HID class descriptor of a keyboard interface with the given
+/// report descriptor, including its length and type prefix.
+fn hid_descriptor(report_descriptor: &[u8]) -> [u8; 9] {
+    let len = (report_descriptor.len() as u16).to_le_bytes();
+    [
+        9,                      // bLength
+        DESCRIPTOR_TYPE_HID,    // bDescriptorType
+        0x11,                   // bcdHID.lower
+        0x01,                   // bcdHID.upper
+        0,                      // bCountryCode: not supported
+        1,                      // bNumDescriptors
+        DESCRIPTOR_TYPE_REPORT, // bDescriptorType
+        len[0],                 // wDescriptorLength.lower
+        len[1],                 // wDescriptorLength.upper
+    ]
+}
+
+/// All the HID interfaces of our device, as selected by cargo features.
+/// Interfaces left out by their feature answer with
+/// `UsbError::Unsupported` when used.
+pub struct Composite<'a, B: UsbBus> {
+    #[cfg(feature = "boot-keyboard")]
+    boot_keyboard: KeyboardClass<'a, B>,
+    #[cfg(feature = "nkro-keyboard")]
+    nkro_keyboard: HIDClass<'a, B>,
+    #[cfg(feature = "nkro-keyboard")]
+    nkro_leds: HostLeds,
+    #[cfg(feature = "nkro-keyboard")]
+    mode: ReportMode,
+    #[cfg(feature = "consumer")]
+    consumer: HIDClass<'a, B>,
+    #[cfg(feature = "mouse")]
+    mouse: HIDClass<'a, B>,
+    #[cfg(feature = "raw-hid")]
+    raw: HIDClass<'a, B>,
+    #[cfg(not(any(
+        feature = "boot-keyboard",
+        feature = "nkro-keyboard",
+        feature = "consumer",
+        feature = "mouse",
+        feature = "raw-hid"
+    )))]
+    _alloc: core::marker::PhantomData<&'a B>,
+}
+
+impl<B: UsbBus> Composite<'_, B> {
+    /// Allocates every enabled interface on `alloc`, in the order of the
+    /// list above, with its endpoints polled every `poll_ms` milliseconds.
+    #[cfg_attr(
+        not(any(
+            feature = "boot-keyboard",
+            feature = "nkro-keyboard",
+            feature = "consumer",
+            feature = "mouse",
+            feature = "raw-hid"
+        )),
+        allow(unused_variables)
+    )]
+    pub fn new(alloc: &UsbBusAllocator<B>, poll_ms: u8) -> Composite<'_, B> {
+        Composite {
+            #[cfg(all(feature = "boot-keyboard", not(feature = "nkro-keyboard")))]
+            boot_keyboard: KeyboardClass::new(alloc, poll_ms),
+            #[cfg(all(feature = "boot-keyboard", feature = "nkro-keyboard"))]
+            boot_keyboard: KeyboardClass::boot_only(alloc, poll_ms),
+            #[cfg(feature = "nkro-keyboard")]
+            nkro_keyboard: HIDClass::new(alloc, hid::NKRO_REPORT_DESCRIPTOR, poll_ms),
+            #[cfg(feature = "nkro-keyboard")]
+            nkro_leds: HostLeds::default(),
+            #[cfg(feature = "nkro-keyboard")]
+            mode: ReportMode::default(),
+            #[cfg(feature = "consumer")]
+            consumer: HIDClass::new_ep_in(alloc, hid::CONSUMER_SYSTEM_REPORT_DESCRIPTOR, poll_ms),
+            #[cfg(feature = "mouse")]
+            mouse: HIDClass::new_ep_in(alloc, hid::MOUSE_REPORT_DESCRIPTOR, poll_ms),
+            #[cfg(feature = "raw-hid")]
+            raw: HIDClass::new(alloc, hid::RAW_REPORT_DESCRIPTOR, poll_ms),
+            #[cfg(not(any(
+                feature = "boot-keyboard",
+                feature = "nkro-keyboard",
+                feature = "consumer",
+                feature = "mouse",
+                feature = "raw-hid"
+            )))]
+            _alloc: core::marker::PhantomData,
+        }
+    }
+
+    /// Polls `device` with every enabled interface, then picks up the
+    /// LED state the host may have sent to the NKRO keyboard. Returns
+    /// `true` if any interface may have new data, like `UsbDevice::poll`.
+    pub fn poll(&mut self, device: &mut UsbDevice<'_, B>) -> bool {
+        let mut classes: heapless::Vec<&mut dyn UsbClass<B>, 5> = heapless::Vec::new();
+        #[cfg(feature = "boot-keyboard")]
+        classes.push(&mut self.boot_keyboard).ok();
+        #[cfg(feature = "nkro-keyboard")]
+        classes.push(&mut self.nkro_keyboard).ok();
+        #[cfg(feature = "consumer")]
+        classes.push(&mut self.consumer).ok();
+        #[cfg(feature = "mouse")]
+        classes.push(&mut self.mouse).ok();
+        #[cfg(feature = "raw-hid")]
+        classes.push(&mut self.raw).ok();
+        let polled = device.poll(&mut classes);
+        drop(classes);
+
+        #[cfg(feature = "nkro-keyboard")]
+        {
+            let mut buf = [0; MAX_PACKET_SIZE as usize];
+            if let Ok(len) = self.nkro_keyboard.pull_raw_output(&mut buf) {
+                self.nkro_leds = HostLeds::from_report(&buf[..len]).unwrap_or(self.nkro_leds);
+            }
+            if let Ok(info) = self.nkro_keyboard.pull_raw_report(&mut buf) {
+                self.nkro_leds = HostLeds::from_report(&buf[..info.len]).unwrap_or(self.nkro_leds);
+            }
+        }
+        polled
+    }
+
+    /// Returns the protocol the host selected for the boot keyboard,
+    /// which is always REPORT without one.
+    pub fn keyboard_protocol(&self) -> Protocol {
+        #[cfg(feature = "boot-keyboard")]
+        return self.boot_keyboard.protocol();
+        #[cfg(not(feature = "boot-keyboard"))]
+        return Protocol::Report;
+    }
+
+    /// Returns the reports the keyboard sends in the REPORT protocol,
+    /// which is always NKRO without a keyboard.
+    pub fn keyboard_mode(&self) -> ReportMode {
+        #[cfg(feature = "nkro-keyboard")]
+        return self.mode;
+        #[cfg(all(feature = "boot-keyboard", not(feature = "nkro-keyboard")))]
+        return self.boot_keyboard.mode();
+        #[cfg(not(any(feature = "boot-keyboard", feature = "nkro-keyboard")))]
+        return ReportMode::Nkro;
+    }
+
+    /// Select the reports the keyboard sends in the REPORT protocol.
+    /// Returns `true` if the host has to enumerate the device again to
+    /// see the change, which is only the case without an NKRO keyboard.
+    /// 6KRO is `UsbError::Unsupported` without the boot keyboard, which
+    /// is the only one with 6KRO reports, and the mode stays as it was.
+    pub fn set_keyboard_mode(&mut self, mode: ReportMode) -> usb_device::Result<bool> {
+        #[cfg(not(feature = "boot-keyboard"))]
+        if mode == ReportMode::SixKro {
+            return Err(UsbError::Unsupported);
+        }
+        #[cfg(feature = "nkro-keyboard")]
+        {
+            self.mode = mode;
+        }
+        #[cfg(all(feature = "boot-keyboard", not(feature = "nkro-keyboard")))]
+        return Ok(self.boot_keyboard.set_mode(mode));
+        #[cfg(not(all(feature = "boot-keyboard", not(feature = "nkro-keyboard"))))]
+        {
+            let _ = mode;
+            Ok(false)
+        }
+    }
+
+    /// Returns `true` if keyboard reports go to the NKRO keyboard.
+    #[cfg(feature = "nkro-keyboard")]
+    fn uses_nkro_keyboard(&self) -> bool {
+        self.keyboard_protocol() == Protocol::Report && self.mode == ReportMode::Nkro
+    }
+
+    /// Returns the LED state last sent by the host to the keyboard
+    /// currently in use.
+    pub fn host_leds(&self) -> HostLeds {
+        #[cfg(feature = "nkro-keyboard")]
+        if self.uses_nkro_keyboard() {
+            return self.nkro_leds;
+        }
+        #[cfg(feature = "boot-keyboard")]
+        return self.boot_keyboard.host_leds();
+        #[cfg(not(feature = "boot-keyboard"))]
+        return HostLeds::default();
+    }
+
+    /// Tries to send a keyboard report. It goes to the boot keyboard if
+    /// the host selected the BOOT protocol, in 6KRO mode or if there is
+    /// no NKRO keyboard, and to the NKRO keyboard otherwise.
+    pub fn push_keyboard(&mut self, report: &NKROReport) -> usb_device::Result<usize> {
+        #[cfg(feature = "nkro-keyboard")]
+        if self.uses_nkro_keyboard() {
+            return self.nkro_keyboard.push_raw_input(report.as_bytes());
+        }
+        #[cfg(feature = "boot-keyboard")]
+        return self.boot_keyboard.push_report(report);
+        #[cfg(not(feature = "boot-keyboard"))]
+        return unsupported(report);
+    }
+
+    /// Tries to send a Consumer or System Control report, including its
+    /// report ID.
+    pub fn push_consumer(&self, report: &[u8]) -> usb_device::Result<usize> {
+        #[cfg(feature = "consumer")]
+        return self.consumer.push_raw_input(report);
+        #[cfg(not(feature = "consumer"))]
+        return unsupported(report);
+    }
+
+    /// Tries to send a mouse report.
+    pub fn push_mouse(&self, report: &MouseReport) -> usb_device::Result<usize> {
+        #[cfg(feature = "mouse")]
+        return self.mouse.push_raw_input(report.as_bytes());
+        #[cfg(not(feature = "mouse"))]
+        return unsupported(report);
+    }
+
+    /// Tries to send a raw HID report of up to `hid::RAW_REPORT_LEN` bytes.
+    pub fn push_raw(&self, data: &[u8]) -> usb_device::Result<usize> {
+        #[cfg(feature = "raw-hid")]
+        return self.raw.push_raw_input(data);
+        #[cfg(not(feature = "raw-hid"))]
+        return unsupported(data);
+    }
+
+    /// Tries to read a raw HID report sent by the host.
+    pub fn pull_raw(&self, data: &mut [u8]) -> usb_device::Result<usize> {
+        #[cfg(feature = "raw-hid")]
+        return self.raw.pull_raw_output(data);
+        #[cfg(not(feature = "raw-hid"))]
+        return unsupported(data);
+    }
+}
+
+/// The result of using an interface left out by its cargo feature.
+#[allow(dead_code)]
+fn unsupported<T: ?Sized>(_: &T) -> usb_device::Result<usize> {
+    Err(UsbError::Unsupported)
+}
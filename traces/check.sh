@@ -0,0 +1,23 @@
+#!/bin/sh
+# Checks every golden trace of this directory, `<name>.golden`, with the
+# simulator built for the keymap next to it, `<name>.toml`. The tests of
+# src/trace.rs check them as well.
+#
+# To make a new one, write the trace, run it with
+#   KEEB_KEYMAP=traces/<name>.toml cargo run --features simulator \
+#       --bin sim --target <host> -- --bytes <trace>
+# check the reports, and paste them in after a `---` line.
+cd "$(dirname "$0")/.." || exit 1
+target=$(rustc -vV | sed -n 's/^host: //p')
+status=0
+for golden in traces/*.golden; do
+    keymap="${golden%.golden}.toml"
+    if KEEB_KEYMAP="$keymap" cargo run -q --features simulator --bin sim \
+        --target "$target" -- --check "$golden"; then
+        echo "ok   $golden"
+    else
+        echo "FAIL $golden"
+        status=1
+    fi
+done
+exit $status